@@ -0,0 +1,137 @@
+use crate::blockchain::{TransactionError, amount::AmountError};
+use crate::wallet::{WalletError, address::AddressError};
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use serde::Serialize;
+use std::fmt;
+
+/// Error answered by a handler, turned into a JSON body with a machine readable `code`
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidAmount { field: &'static str, error: AmountError },
+    InvalidHex { field: &'static str },
+    InvalidAddress { field: &'static str, error: AddressError },
+    InvalidKey(WalletError),
+    InvalidQuery(String),
+    Rejected(TransactionError),
+    // what was looked for, such as "block 12"
+    NotFound(String),
+    MinerRunning,
+    MinerStopped,
+    MiningCancelled,
+    StaleBlock,
+    InvalidBlock,
+    Storage(std::io::Error),
+    Internal(String),
+}
+
+/// Body of every error response
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    // the transaction rule that was broken, for rejected transactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<TransactionError>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidAmount { .. } => "invalid_amount",
+            ApiError::InvalidHex { .. } => "invalid_hex",
+            ApiError::InvalidAddress { .. } => "invalid_address",
+            ApiError::InvalidKey(WalletError::InvalidMnemonic | WalletError::InvalidWordCount(_)) => {
+                "invalid_mnemonic"
+            }
+            ApiError::InvalidKey(WalletError::InvalidAddress(_)) => "invalid_address",
+            ApiError::InvalidKey(_) => "invalid_key",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::Rejected(_) => "transaction_rejected",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MinerRunning => "miner_running",
+            ApiError::MinerStopped => "miner_stopped",
+            ApiError::MiningCancelled => "mining_cancelled",
+            ApiError::StaleBlock => "stale_block",
+            ApiError::InvalidBlock => "invalid_block",
+            ApiError::Storage(_) => "storage",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidAmount { field, error } => write!(f, "invalid {}: {}", field, error),
+            ApiError::InvalidHex { field } => write!(f, "{} is not hex", field),
+            ApiError::InvalidAddress { field, error } => write!(f, "invalid {}: {}", field, error),
+            ApiError::InvalidKey(e) => write!(f, "{}", e),
+            ApiError::InvalidQuery(message) => write!(f, "{}", message),
+            ApiError::Rejected(e) => write!(f, "transaction rejected: {}", e),
+            ApiError::NotFound(what) => write!(f, "{} not found", what),
+            ApiError::MinerRunning => write!(f, "auto-miner is already running"),
+            ApiError::MinerStopped => write!(f, "auto-miner is not running"),
+            ApiError::MiningCancelled => write!(f, "mining cancelled, the chain tip changed"),
+            ApiError::StaleBlock => write!(f, "mined block is stale, the chain tip changed"),
+            ApiError::InvalidBlock => write!(f, "block rejected"),
+            ApiError::Storage(e) => write!(f, "storage failure: {}", e),
+            ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<WalletError> for ApiError {
+    fn from(e: WalletError) -> Self {
+        ApiError::InvalidKey(e)
+    }
+}
+
+impl From<TransactionError> for ApiError {
+    fn from(e: TransactionError) -> Self {
+        ApiError::Rejected(e)
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidAmount { .. }
+            | ApiError::InvalidHex { .. }
+            | ApiError::InvalidAddress { .. }
+            | ApiError::InvalidKey(_)
+            | ApiError::InvalidQuery(_)
+            | ApiError::InvalidBlock => StatusCode::BAD_REQUEST,
+            // the pool already holds this transaction or its nonce
+            ApiError::Rejected(TransactionError::Duplicate | TransactionError::InvalidNonce { .. }) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::Rejected(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MinerRunning
+            | ApiError::MinerStopped
+            | ApiError::MiningCancelled
+            | ApiError::StaleBlock => StatusCode::CONFLICT,
+            ApiError::Storage(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let reason = match self {
+            ApiError::Rejected(e) => Some(e.clone()),
+            _ => None,
+        };
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            reason,
+        })
+    }
+}
@@ -1,12 +1,14 @@
-use std::{collections::HashMap, str, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet}, env, net::Ipv4Addr, path::{Path, PathBuf}, str, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant, SystemTime}};
 
-use actix_web::{App, HttpResponse, HttpServer, cookie::time::format_description::parse, web};
-use log::{debug, info};
+use actix_web::{App, HttpResponse, HttpServer, web};
+use log::{debug, info, warn};
 use serde::{Deserialize , Serialize};
-use sha2::digest::block_buffer::Block;
-use crate::blockchain::{transaction::Transaction as BlockchainTransaction, BlockChain};
-use crate::wallet::Wallet;
-use serde_json;
+use tokio::net::TcpStream;
+use crate::blockchain::{Serialization, amount::Amount, merkle::MerkleProof, miner::Miner, network::{Network, NetworkParams}, transaction::{Transaction as BlockchainTransaction, TransactionStatus}, Block, BlockChain, BlockSearch, BlockSearchResult};
+use crate::wallet::{Transaction as WalletTransaction, Wallet, WalletData, address::Address, hd::HdWallet};
+
+mod error;
+pub use error::ApiError;
 
 #[derive( Serialize, Debug)]
 pub struct  TransactionInBlockChain {
@@ -17,6 +19,7 @@ transactions :Vec<BlockchainTransaction>
 #[derive(Clone, Debug)]
 pub struct ApiServer {
     port: u16,
+    network: Network,
     /*
     clone the api server , we will only increase the reference coubt of ARc,
     and the mutex remain only one
@@ -24,7 +27,274 @@ pub struct ApiServer {
      */
     cache: Arc<Mutex<HashMap<String, BlockChain>>>, // wallet_address -> blockchain
     neighbour : Arc<Mutex<Vec<String>>>,
+    client: reqwest::Client,
+    // ids of gossiped transactions and blocks we already handled, so they are relayed only once
+    seen: Arc<Mutex<HashSet<Vec<u8>>>>,
+    // solves blocks outside the cache lock, cancelled whenever our tip changes
+    miner: Arc<Miner>,
+    auto_miner: Arc<Mutex<AutoMiner>>,
+
+}
+
+/// Chain as served to neighbours at `/chain`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChainResponse {
+    length: usize,
+    chain: Vec<Block>,
+}
+
+/// Inclusion proof of a transaction, checked with `merkle::verify_proof` against `merkle_root`
+#[derive(Serialize, Debug)]
+struct ProofResponse {
+    block_index: usize,
+    block_hash: String,
+    merkle_root: String,
+    proof: MerkleProof,
+}
+
+#[derive(Serialize, Debug)]
+struct NonceResponse {
+    address: String,
+    next_nonce: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct SubmitResponse {
+    message: &'static str,
+    transaction_id: String,
+}
+
+/// Receipt of a transaction served at `/tx/{id}`
+#[derive(Serialize, Debug)]
+struct TransactionReceipt {
+    id: String,
+    #[serde(flatten)]
+    status: TransactionStatus,
+    sender: String,
+    recipient: String,
+    value: Amount,
+}
+
+#[derive(Serialize, Debug)]
+struct SearchResponse<'a> {
+    hash: String,
+    block: &'a Block,
+}
+
+#[derive(Serialize, Debug)]
+struct MiningResponse {
+    height: usize,
+    hash: String,
+    hashes: u64,
+    elapsed_ms: u128,
+    hashrate: u64,
+    threads: usize,
+}
+
+/// `from` and `limit` query parameters of the paged explorer endpoints
+#[derive(Deserialize, Debug)]
+pub struct Paging {
+    #[serde(default)]
+    from: usize,
+    limit: Option<usize>,
+}
+
+impl Paging {
+    const DEFAULT_LIMIT: usize = 20;
+    const MAX_LIMIT: usize = 100;
+
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT).min(Self::MAX_LIMIT)
+    }
+
+    /// Range of the requested page within `total` items
+    fn range(&self, total: usize) -> std::ops::Range<usize> {
+        let start = self.from.min(total);
+        start..start.saturating_add(self.limit()).min(total)
+    }
+}
+
+/// Decoded transaction as shown by the explorer
+#[derive(Serialize, Debug)]
+struct TransactionView {
+    id: String,
+    sender: String,
+    recipient: String,
+    value: Amount,
+    fee: Amount,
+    nonce: u64,
+}
+
+impl TransactionView {
+    // `id` is the hash of the bytes stored in the block, older encodings hash differently
+    fn new(id: &[u8], tx: &BlockchainTransaction) -> Self {
+        TransactionView {
+            id: hex::encode(id),
+            sender: String::from_utf8_lossy(&tx.sender_address).into_owned(),
+            recipient: String::from_utf8_lossy(&tx.recipient_address).into_owned(),
+            value: tx.value,
+            fee: tx.fee,
+            nonce: tx.nonce,
+        }
+    }
+}
+
+/// Block as shown by the explorer, hashes in hex and transactions decoded
+#[derive(Serialize, Debug)]
+struct BlockView {
+    height: usize,
+    hash: String,
+    previous_hash: String,
+    merkle_root: String,
+    nonce: u64,
+    difficulty: u32,
+    time_stamp: u128,
+    transaction_count: usize,
+    transactions: Vec<TransactionView>,
+}
+
+impl BlockView {
+    fn new(height: usize, block: &Block) -> Self {
+        let transactions = block
+            .transactions()
+            .iter()
+            .zip(block.transaction_hashes())
+            .map(|(tx, tx_hash)| match BlockchainTransaction::deserialization(tx) {
+                Ok(tx) => TransactionView::new(&tx_hash, &tx),
+                // validated blocks only hold decodable transactions, keep the id regardless
+                Err(_) => TransactionView {
+                    id: hex::encode(tx_hash),
+                    sender: String::new(),
+                    recipient: String::new(),
+                    value: Amount::ZERO,
+                    fee: Amount::ZERO,
+                    nonce: 0,
+                },
+            })
+            .collect::<Vec<TransactionView>>();
+
+        BlockView {
+            height,
+            hash: hex::encode(block.hash()),
+            previous_hash: hex::encode(block.previous_hash()),
+            merkle_root: hex::encode(block.merkle_root()),
+            nonce: block.nonce(),
+            difficulty: block.difficulty(),
+            time_stamp: block.time_stamp(),
+            transaction_count: transactions.len(),
+            transactions,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct BlocksPage {
+    from: usize,
+    limit: usize,
+    total: usize,
+    blocks: Vec<BlockView>,
+}
+
+#[derive(Serialize, Debug)]
+struct AddressTransaction {
+    block_height: usize,
+    block_hash: String,
+    #[serde(flatten)]
+    transaction: TransactionView,
+}
+
+#[derive(Serialize, Debug)]
+struct AddressTransactionsPage {
+    address: String,
+    from: usize,
+    limit: usize,
+    total: usize,
+    transactions: Vec<AddressTransaction>,
+}
+
+/// When the auto-miner mines, given to `/miner/start`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum MiningMode {
+    // one block after the other
+    #[default]
+    Continuous,
+    // as soon as the pool holds at least `transactions` transactions
+    Pool { transactions: usize },
+    // one block every `interval_secs` seconds
+    Timer { interval_secs: u64 },
+}
+
+/// State of the background miner of a node
+#[derive(Debug, Default)]
+struct AutoMiner {
+    mode: Option<MiningMode>,
+    // bumped on every start and stop, a mining loop ends once it is not the current run
+    run: u64,
+    blocks_mined: u64,
+    last_block_height: Option<usize>,
+    last_block_time: Option<SystemTime>,
+}
+
+#[derive(Serialize, Debug)]
+struct AutoMinerStatus {
+    running: bool,
+    mode: Option<MiningMode>,
+    blocks_mined: u64,
+    last_block_height: Option<usize>,
+    // unix time in seconds
+    last_block_time: Option<u64>,
+    hashrate: u64,
+    threads: usize,
+}
+
+/// `/get-wallet?words=`, length of the mnemonic to generate
+#[derive(Deserialize, Debug)]
+struct NewWallet {
+    #[serde(default = "NewWallet::default_words")]
+    words: usize,
+}
+
+impl NewWallet {
+    fn default_words() -> usize {
+        12
+    }
+}
+
+/// Body of `/wallet/derive`, restores account `account` of a mnemonic
+#[derive(Deserialize, Debug)]
+pub struct DeriveWallet {
+    mnemonic: String,
+    #[serde(default)]
+    passphrase: String,
+    #[serde(default)]
+    account: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct HdWalletData {
+    // only sent when the mnemonic was just generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+    account: u32,
+    #[serde(flatten)]
+    wallet: WalletData,
+}
+
+#[derive(Serialize, Debug)]
+struct NetworkResponse {
+    network: Network,
+    // hash of the genesis block, part of every signed transaction
+    chain_id: String,
+    #[serde(flatten)]
+    params: &'static NetworkParams,
+}
 
+#[derive(Serialize, Debug)]
+struct ConsensusResponse {
+    replaced: bool,
+    length: usize,
+    neighbours: Vec<String>,
 }
 
 
@@ -34,13 +304,16 @@ pub struct Transaction {
   pub public_key : String,
   pub blockchain_address :String,
   pub recipient_address:String,
-  pub amount :String
+  pub amount :String,
+  // optional, no fee when missing
+  #[serde(default)]
+  pub fee :Option<String>,
 
 }
 //http:://localhost:5000/amount/0x12345
 #[derive(Serialize)]
 struct QueryAmount {
-    amount: f64,
+    amount: Amount,
 }
 
 
@@ -50,18 +323,42 @@ impl ApiServer {
       const NEIGHBOUR_IP_RANGE_START : u8 = 0;
       const NEIGHBOUR_IP_RANGE_END : u8 = 1;
       const NEIGHBOUR_IP_SYNC_TIME : u8 = 20;
+      const DATA_DIR : &str = "data";
+      // directory under DATA_DIR holding keystores
+      const KEYSTORE_DIR : &str = "keystore";
+      const MINER_KEYSTORE_ENV : &str = "MINER_KEYSTORE";
+      const MINER_KEYSTORE_PASSWORD_ENV : &str = "MINER_KEYSTORE_PASSWORD";
+      // mainnet, testnet or devnet, mainnet when unset
+      const NETWORK_ENV : &str = "NETWORK";
+      // port ranges above are offsets from this port, ip ranges offsets from the last byte of HOST
+      const BLOCKCHAIN_PORT_BASE : u16 = 5000;
+      const HOST : Ipv4Addr = Ipv4Addr::LOCALHOST;
+      const NEIGHBOUR_TIMEOUT : Duration = Duration::from_secs(1);
+      const SEEN_CAPACITY : usize = 10_000;
+      // how often an idle auto-miner checks whether it is due
+      const AUTO_MINER_POLL_TIME : Duration = Duration::from_millis(500);
 
 
    pub fn new(port: u16) -> Self {
     let cache = Arc::new(Mutex::new(HashMap::new()));
   let neighbour =  Arc::new(Mutex::new(vec![]));
-    let mut api_server = ApiServer {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("Failed to build http client");
+    let network = Self::network_from_env();
+    let api_server = ApiServer {
         port,
+        network,
         cache,
         neighbour,
+        client,
+        seen: Arc::new(Mutex::new(HashSet::new())),
+        miner: Arc::new(Miner::default()),
+        auto_miner: Arc::new(Mutex::new(AutoMiner::default())),
     };
 
-    let wallet_miner = Wallet::new();
+    let wallet_miner = Self::miner_wallet(network, port);
     let miner_address = wallet_miner.get_adress();
 
   {
@@ -73,42 +370,281 @@ impl ApiServer {
     need to release the lock before returing to the api_server,
     no  unlock method , only  way to unlock thge mutex is let go out of  its scope
      */
-    let mut unlock_cache =api_server.cache.lock().unwrap();
-    unlock_cache.insert("blockchain".to_string(), BlockChain::new(miner_address));
+    let mut unlock_cache = lock(&api_server.cache);
+    let block_chain = BlockChain::open(miner_address, Self::chain_dir(network, port), network)
+        .expect("Failed to open blockchain storage");
+    unlock_cache.insert("blockchain".to_string(), block_chain);
 
 
   }
 
-return api_server;
+api_server
 }
+
+    fn network_from_env() -> Network {
+        match env::var(Self::NETWORK_ENV) {
+            Ok(name) => name.parse().unwrap_or_else(|e| panic!("{}: {}", Self::NETWORK_ENV, e)),
+            Err(_) => Network::default(),
+        }
+    }
+
+    // mainnet keeps the layout it had before networks existed
+    fn chain_dir(network: Network, port: u16) -> PathBuf {
+        match network {
+            Network::Mainnet => Path::new(Self::DATA_DIR).join(port.to_string()),
+            _ => Path::new(Self::DATA_DIR).join(network.to_string()).join(port.to_string()),
+        }
+    }
+
+    /*
+    the wallet rewards are paid to. With MINER_KEYSTORE=<name> it is the keystore
+    data/keystore/<name>-<port>.json opened with MINER_KEYSTORE_PASSWORD, created
+    on the first start, so the node keeps its identity across restarts. Every node
+    of the process has its own keystore: a node refuses transactions sent by its
+    own miner, so nodes sharing one could never spend the rewards. Without it
+    every start mines to a throwaway wallet.
+     */
+    fn miner_wallet(network: Network, port: u16) -> Wallet {
+        let Ok(name) = env::var(Self::MINER_KEYSTORE_ENV) else {
+            return Wallet::new(network);
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            panic!("{} must be a plain name, got {:?}", Self::MINER_KEYSTORE_ENV, name);
+        }
+        let password = env::var(Self::MINER_KEYSTORE_PASSWORD_ENV).unwrap_or_else(|_| {
+            panic!("{} is set but {} is not", Self::MINER_KEYSTORE_ENV, Self::MINER_KEYSTORE_PASSWORD_ENV)
+        });
+
+        let path = Path::new(Self::DATA_DIR)
+            .join(Self::KEYSTORE_DIR)
+            .join(format!("{}-{}.json", name, port));
+        if !path.exists() {
+            let wallet = Wallet::new(network);
+            if let Err(e) = wallet.save_encrypted(&path, &password) {
+                panic!("Failed to create miner keystore {}: {}", path.display(), e);
+            }
+            info!("created miner keystore {} for {}", path.display(), wallet.get_adress());
+            return wallet;
+        }
+
+        let wallet = Wallet::load_encrypted(&path, &password)
+            .unwrap_or_else(|e| panic!("Failed to open miner keystore {}: {}", path.display(), e));
+        if wallet.network() != network {
+            panic!(
+                "miner keystore {} is a {} wallet, this node runs {}",
+                path.display(),
+                wallet.network(),
+                network
+            );
+        }
+        info!("mining to {} from keystore {}", wallet.get_adress(), path.display());
+        wallet
+    }
+
+    /// Parse an address of the network this node runs
+    fn parse_address(&self, value: &str, field: &'static str) -> Result<Address, ApiError> {
+        Address::parse_for(value, self.network)
+            .map_err(|error| ApiError::InvalidAddress { field, error })
+    }
+
+    /// Run `f` on the node's chain, holding the cache lock for the call only
+    fn with_chain<R>(&self, f: impl FnOnce(&mut BlockChain) -> R) -> Result<R, ApiError> {
+        let mut unlock_cache = lock(&self.cache);
+        let block_chain = unlock_cache
+            .get_mut("blockchain")
+            .ok_or_else(|| ApiError::Internal("blockchain is not loaded".to_string()))?;
+        Ok(f(block_chain))
+    }
+
     pub async fn get_amount(
         data: web::Data<Arc<ApiServer>>,
         path: web::Path<String>,
-    ) -> HttpResponse {
-        let address = path.into_inner();
-        let api_server = data.get_ref();
-        let unlock_cache = api_server.cache.lock().unwrap();
-        let block_chain = unlock_cache.get("blockchain").unwrap();
-        let amount = block_chain.calculate_total_amount(address);
-        let amount_return = QueryAmount { amount };
+    ) -> Result<HttpResponse, ApiError> {
+        let address = data.get_ref().parse_address(&path.into_inner(), "address")?;
+        let amount = data
+            .get_ref()
+            .with_chain(|block_chain| block_chain.calculate_total_amount(address.to_string()))?
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
 
-        HttpResponse::Ok().json(amount_return)
+        Ok(HttpResponse::Ok().json(QueryAmount { amount }))
     }
 
 
-   pub async fn mining(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+    pub async fn get_nonce(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+    ) -> Result<HttpResponse, ApiError> {
+        let address = data.get_ref().parse_address(&path.into_inner(), "address")?.to_string();
+        let next_nonce = data
+            .get_ref()
+            .with_chain(|block_chain| block_chain.next_nonce(&address))?;
+
+        Ok(HttpResponse::Ok().json(NonceResponse {
+            address,
+            next_nonce,
+        }))
+    }
+
+    pub async fn get_mempool(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let stats = data.get_ref().with_chain(|block_chain| {
+            block_chain.expire_pool();
+            block_chain.transaction_pool.stats()
+        })?;
+
+        Ok(HttpResponse::Ok().json(stats))
+    }
+
+    pub async fn mining(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let response = data.get_ref().mine_once().await?;
+        Ok(HttpResponse::Ok().json(response))
+    }
+
+    /*
+    the template is built under the lock, solved on the miner's threads with
+    the lock released, and only appended if the tip is still the one it was
+    built on
+     */
+    async fn mine_once(&self) -> Result<MiningResponse, ApiError> {
+        let (template, generation) = self.with_chain(|block_chain| {
+            let generation = self.miner.generation();
+            block_chain.block_template().map(|template| (template, generation))
+        })??;
+
+        let miner = Arc::clone(&self.miner);
+        let report = tokio::task::spawn_blocking(move || miner.mine(&template, generation))
+            .await
+            .map_err(|e| ApiError::Internal(format!("mining job failed: {}", e)))?;
+        let block = report.block.clone().ok_or(ApiError::MiningCancelled)?;
+
+        let height = self.with_chain(|block_chain| {
+            block_chain
+                .add_block(block.clone())
+                .map(|appended| appended.then(|| block_chain.chain.len() - 1))
+        })??
+        .ok_or(ApiError::StaleBlock)?;
+        self.miner.cancel();
+
+        self.mark_seen(block.hash());
+        self.broadcast("/gossip/block", &block);
+
+        Ok(MiningResponse {
+            height,
+            hash: hex::encode(block.hash()),
+            hashes: report.hashes,
+            elapsed_ms: report.elapsed.as_millis(),
+            hashrate: report.hashrate(),
+            threads: self.miner.threads(),
+        })
+    }
+
+    pub async fn start_miner(
+        data: web::Data<Arc<ApiServer>>,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, ApiError> {
+        // only a missing body means the default, a mistyped one must not start continuous mining
+        let mode = if body.iter().all(u8::is_ascii_whitespace) {
+            MiningMode::default()
+        } else {
+            serde_json::from_slice::<MiningMode>(&body)
+                .map_err(|e| ApiError::InvalidQuery(format!("invalid mining mode: {}", e)))?
+        };
+        if let MiningMode::Pool { transactions: 0 } | MiningMode::Timer { interval_secs: 0 } = mode {
+            return Err(ApiError::InvalidQuery(
+                "mining mode needs a non-zero threshold".to_string(),
+            ));
+        }
+
         let api_server = data.get_ref();
-        let mut unlock_cache = api_server.cache.lock().unwrap();
-        let block_chain = unlock_cache.get_mut("blockchain").unwrap();
-        let is_mined = block_chain.mining();
-        if (!is_mined) {
-            return HttpResponse::InternalServerError().json("mining fail");
+        let run = {
+            let mut auto_miner = lock(&api_server.auto_miner);
+            if auto_miner.mode.is_some() {
+                return Err(ApiError::MinerRunning);
+            }
+            auto_miner.mode = Some(mode);
+            auto_miner.run += 1;
+            auto_miner.run
+        };
+
+        info!("port {}: auto-miner started in {:?} mode", api_server.port, mode);
+        tokio::spawn(Arc::clone(api_server).auto_mine(run, mode));
+        Ok(HttpResponse::Ok().json(api_server.auto_miner_status()))
+    }
+
+    pub async fn stop_miner(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let api_server = data.get_ref();
+        {
+            let mut auto_miner = lock(&api_server.auto_miner);
+            if auto_miner.mode.take().is_none() {
+                return Err(ApiError::MinerStopped);
+            }
+            auto_miner.run += 1;
         }
+        // do not wait for the block in progress, it may take long at a high difficulty
+        api_server.miner.cancel();
+
+        info!("port {}: auto-miner stopped", api_server.port);
+        Ok(HttpResponse::Ok().json(api_server.auto_miner_status()))
+    }
+
+    pub async fn miner_status(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+        HttpResponse::Ok().json(data.get_ref().auto_miner_status())
+    }
+
+    fn auto_miner_status(&self) -> AutoMinerStatus {
+        let auto_miner = lock(&self.auto_miner);
+        AutoMinerStatus {
+            running: auto_miner.mode.is_some(),
+            mode: auto_miner.mode,
+            blocks_mined: auto_miner.blocks_mined,
+            last_block_height: auto_miner.last_block_height,
+            last_block_time: auto_miner.last_block_time.map(|time| {
+                time.duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            }),
+            hashrate: self.miner.hashrate(),
+            threads: self.miner.threads(),
+        }
+    }
+
+    fn is_current_run(&self, run: u64) -> bool {
+        lock(&self.auto_miner).run == run
+    }
+
+    async fn auto_mine(self: Arc<Self>, run: u64, mode: MiningMode) {
+        let mut last_attempt = Instant::now();
+        while self.is_current_run(run) {
+            let due = match mode {
+                MiningMode::Continuous => Ok(true),
+                MiningMode::Pool { transactions } => self.with_chain(|block_chain| {
+                    block_chain.transaction_pool.len() >= transactions
+                }),
+                MiningMode::Timer { interval_secs } => {
+                    Ok(last_attempt.elapsed() >= Duration::from_secs(interval_secs))
+                }
+            };
+            if !matches!(due, Ok(true)) {
+                tokio::time::sleep(Self::AUTO_MINER_POLL_TIME).await;
+                continue;
+            }
 
-        HttpResponse::Ok().json("mining ok")
+            last_attempt = Instant::now();
+            match self.mine_once().await {
+                Ok(response) => {
+                    let mut auto_miner = lock(&self.auto_miner);
+                    auto_miner.blocks_mined += 1;
+                    auto_miner.last_block_height = Some(response.height);
+                    auto_miner.last_block_time = Some(SystemTime::now());
+                }
+                // a neighbour beat us to this height, start over on the new tip
+                Err(ApiError::MiningCancelled | ApiError::StaleBlock) => {}
+                Err(e) => {
+                    warn!("auto-miner: {}", e);
+                    tokio::time::sleep(Self::AUTO_MINER_POLL_TIME).await;
+                }
+            }
+        }
     }
 
-       
 async fn get_wallet() -> HttpResponse {
         HttpResponse::Ok().content_type("text/html").body(
             r#"
@@ -128,6 +664,7 @@ async fn get_wallet() -> HttpResponse {
                             $("\#public_key").val(response['public_key'])
                             $("\#private_key").val(response['private_key'])
                             $("\#blockchain_address").val(response['blockchain_address'])
+                            $("\#mnemonic").val(response['mnemonic'])
                         },
                         error: function(error) {
                             console.log(error)
@@ -209,6 +746,10 @@ async fn get_wallet() -> HttpResponse {
                     <p>Blockchain address</p>
                    <textarea id="blockchain_address" row="1" cols="100">
                    </textarea>
+
+                    <p>Recovery words, write them down to restore the wallet</p>
+                   <textarea id="mnemonic" row="2" cols="100">
+                   </textarea>
                 </div>
 
                 <div>
@@ -230,65 +771,526 @@ async fn get_wallet() -> HttpResponse {
 pub  async fn get_transaction_handler(
     data:web::Data<Arc<ApiServer>>,
     transaction : web::Json<Transaction>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     
 let tx = transaction.into_inner();
 debug!("receive json info:{:?}",tx);
 // pase return Result
- let amount = tx.amount.parse::<f64>().unwrap();
+ let amount = tx
+    .amount
+    .parse::<Amount>()
+    .map_err(|error| ApiError::InvalidAmount { field: "amount", error })?;
+ let fee = tx
+    .fee
+    .as_deref()
+    .map(str::parse::<Amount>)
+    .transpose()
+    .map_err(|error| ApiError::InvalidAmount { field: "fee", error })?
+    .unwrap_or(Amount::ZERO);
 
 //need to create wallet instance from the transaction
 
-let mut wallet = Wallet::new_from(&tx.public_key , &tx.private_key , &tx.blockchain_address);
-let wallet_tx = wallet.sign_transaction(&tx.recipient_address , amount);
-let api_server = data.get_ref();
-let mut unlock_cache = api_server.cache.lock().unwrap();
-let block_chain = unlock_cache.get_mut("blockchain").unwrap();
-let add_result = block_chain.add_transaction(&wallet_tx);
-if !add_result{
-    info!("add transaction to blockchain failed");
-    return  HttpResponse::InternalServerError().json("add transaction to blockchain Failed");
+let sender = data.get_ref().parse_address(&tx.blockchain_address, "blockchain_address")?;
+let recipient = data.get_ref().parse_address(&tx.recipient_address, "recipient_address")?;
+let mut wallet = Wallet::new_from(&tx.public_key , &tx.private_key , &sender.to_string())?;
+let (nonce, chain_id) = data.get_ref().with_chain(|block_chain| {
+    (block_chain.next_nonce(&sender.to_string()), block_chain.chain_id())
+})?;
+let wallet_tx = wallet.sign_transaction(&recipient.to_string(), amount, fee, nonce, &chain_id);
+data.get_ref().submit_transaction(wallet_tx)
+
+
 }
 
-info!("add transaction to blockchain ok");
-return  HttpResponse::Ok().json("add transaction to blockchain ok");
+    /*
+    the client signs the transaction itself and only sends the result, so the
+    private key never leaves the client. The signature has to cover the chain
+    id given at /network.
+     */
+    pub async fn signed_transaction_handler(
+        data: web::Data<Arc<ApiServer>>,
+        transaction: web::Json<WalletTransaction>,
+    ) -> Result<HttpResponse, ApiError> {
+        let tx = transaction.into_inner();
+        debug!("receive signed transaction:{:?}", tx);
+
+        // the pool checks both the signature and that the key owns the sender address
+        data.get_ref().submit_transaction(tx)
+    }
 
+    /// Add a signed transaction to the pool and gossip it to the neighbours
+    fn submit_transaction(&self, wallet_tx: WalletTransaction) -> Result<HttpResponse, ApiError> {
+        if let Err(e) = self.with_chain(|block_chain| block_chain.add_transaction(&wallet_tx))? {
+            info!("add transaction to blockchain failed: {}", e);
+            return Err(e.into());
+        }
 
-}
+        info!("add transaction to blockchain ok");
+        let transaction_id = BlockchainTransaction::from_wallet_transaction(&wallet_tx).hash();
+        self.mark_seen(transaction_id.clone());
+        self.broadcast("/gossip/transaction", &wallet_tx);
+        Ok(HttpResponse::Ok().json(SubmitResponse {
+            message: "add transaction to blockchain ok",
+            transaction_id: hex::encode(transaction_id),
+        }))
+    }
 
-    pub async fn show_transaction(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
-        let api_server = data.get_ref();
-        let unlock_cache = api_server.cache.lock().unwrap();
-        let block_chain = unlock_cache.get("blockchain").unwrap();
-        let mut get_transactions = TransactionInBlockChain {
-            transaction_count: 0,
-            transactions: Vec::<BlockchainTransaction>::new(),
+    pub async fn get_transaction_receipt(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+    ) -> Result<HttpResponse, ApiError> {
+        let tx_hash = decode_hex(&path.into_inner(), "transaction id")?;
+
+        let (tx, status) = data
+            .get_ref()
+            .with_chain(|block_chain| block_chain.find_transaction(&tx_hash))?
+            .ok_or_else(|| ApiError::NotFound(format!("transaction {}", hex::encode(&tx_hash))))?;
+
+        Ok(HttpResponse::Ok().json(TransactionReceipt {
+            id: hex::encode(&tx_hash),
+            status,
+            sender: String::from_utf8_lossy(&tx.sender_address).into_owned(),
+            recipient: String::from_utf8_lossy(&tx.recipient_address).into_owned(),
+            value: tx.value,
+        }))
+    }
+
+    pub async fn show_transaction(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let transactions = data
+            .get_ref()
+            .with_chain(|block_chain| block_chain.get_transactions())?;
+        let get_transactions = TransactionInBlockChain {
+            transaction_count: transactions.len(),
+            transactions,
         };
-        get_transactions.transactions = block_chain.get_transactions();
-        get_transactions.transaction_count = get_transactions.transactions.len();
         debug!("show transactions in chain:{:?}", get_transactions);
-        HttpResponse::Ok().json(get_transactions)
+        Ok(HttpResponse::Ok().json(get_transactions))
     }
 
 
-    async fn get_wallet_handler() -> HttpResponse{ 
-        let wallet_user = Wallet::new();
-        let wallet_data = wallet_user.get_wallet_data();
-         HttpResponse::Ok().json(wallet_data)
+    /// A new HD wallet with its mnemonic, and the keys of its first account
+    async fn get_wallet_handler(
+        data: web::Data<Arc<ApiServer>>,
+        query: web::Query<NewWallet>,
+    ) -> Result<HttpResponse, ApiError> {
+        let hd_wallet = HdWallet::generate(query.words)?;
+        let wallet_user = hd_wallet.derive_account(0, data.get_ref().network)?;
+        Ok(HttpResponse::Ok().json(HdWalletData {
+            mnemonic: Some(hd_wallet.phrase()),
+            account: 0,
+            wallet: wallet_user.get_wallet_data(),
+        }))
+    }
+
+    /// Keys of any account of a mnemonic, the same ones every time
+    pub async fn derive_wallet(
+        data: web::Data<Arc<ApiServer>>,
+        body: web::Json<DeriveWallet>,
+    ) -> Result<HttpResponse, ApiError> {
+        let network = data.get_ref().network;
+        let wallet_user =
+            Wallet::from_mnemonic(&body.mnemonic, &body.passphrase, body.account, network)?;
+        Ok(HttpResponse::Ok().json(HdWalletData {
+            mnemonic: None,
+            account: body.account,
+            wallet: wallet_user.get_wallet_data(),
+        }))
     }
     
+    pub async fn get_chain(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let chain = data
+            .get_ref()
+            .with_chain(|block_chain| block_chain.chain.clone())?;
+
+        Ok(HttpResponse::Ok().json(ChainResponse {
+            length: chain.len(),
+            chain,
+        }))
+    }
+
+    pub async fn get_proof(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+    ) -> Result<HttpResponse, ApiError> {
+        let tx_hash = decode_hex(&path.into_inner(), "transaction hash")?;
+
+        data.get_ref().with_chain(|block_chain| {
+            let (block_index, proof) = block_chain.transaction_proof(&tx_hash).ok_or_else(|| {
+                ApiError::NotFound(format!("transaction {} in the chain", hex::encode(&tx_hash)))
+            })?;
+
+            let block = &block_chain[block_index];
+            Ok(HttpResponse::Ok().json(ProofResponse {
+                block_index,
+                block_hash: hex::encode(block.hash()),
+                merkle_root: hex::encode(block.merkle_root()),
+                proof,
+            }))
+        })?
+    }
+
+    /// `/blocks?from=&limit=`, blocks in chain order starting at height `from`
+    pub async fn get_blocks(
+        data: web::Data<Arc<ApiServer>>,
+        paging: web::Query<Paging>,
+    ) -> Result<HttpResponse, ApiError> {
+        data.get_ref().with_chain(|block_chain| {
+            let total = block_chain.chain.len();
+            let range = paging.range(total);
+
+            HttpResponse::Ok().json(BlocksPage {
+                from: paging.from,
+                limit: paging.limit(),
+                total,
+                blocks: range
+                    .clone()
+                    .zip(&block_chain.chain[range])
+                    .map(|(height, block)| BlockView::new(height, block))
+                    .collect(),
+            })
+        })
+    }
+
+    pub async fn get_block(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<usize>,
+    ) -> Result<HttpResponse, ApiError> {
+        let height = path.into_inner();
+        data.get_ref().with_chain(|block_chain| {
+            let block = block_chain
+                .chain
+                .get(height)
+                .ok_or_else(|| ApiError::NotFound(format!("block {}", height)))?;
+            Ok(HttpResponse::Ok().json(BlockView::new(height, block)))
+        })?
+    }
+
+    pub async fn get_block_by_hash(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+    ) -> Result<HttpResponse, ApiError> {
+        let hash = decode_hex(&path.into_inner(), "block hash")?;
+
+        data.get_ref().with_chain(|block_chain| {
+            let height = block_chain
+                .height_of_block(&hash)
+                .ok_or_else(|| ApiError::NotFound(format!("block {}", hex::encode(&hash))))?;
+            Ok(HttpResponse::Ok().json(BlockView::new(height, &block_chain[height])))
+        })?
+    }
+
+    /// `/address/{addr}/transactions?from=&limit=`, confirmed transactions from or to an address, oldest first
+    pub async fn get_address_transactions(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+        paging: web::Query<Paging>,
+    ) -> Result<HttpResponse, ApiError> {
+        let address = data.get_ref().parse_address(&path.into_inner(), "address")?.to_string();
+        data.get_ref().with_chain(|block_chain| {
+            let transactions = block_chain.address_transactions(&address);
+            let total = transactions.len();
+            let range = paging.range(total);
+
+            let transactions = transactions[range]
+                .iter()
+                .map(|(height, tx_hash, tx)| AddressTransaction {
+                    block_height: *height,
+                    block_hash: hex::encode(block_chain[*height].hash()),
+                    transaction: TransactionView::new(tx_hash, tx),
+                })
+                .collect();
+            HttpResponse::Ok().json(AddressTransactionsPage {
+                address: address.clone(),
+                from: paging.from,
+                limit: paging.limit(),
+                total,
+                transactions,
+            })
+        })
+    }
+
+    /*
+    /search/{kind}/{value}, kind is one of index, previous-hash, hash,
+    timestamp, transaction or nonce. Hashes are hex encoded.
+     */
+    pub async fn search_block(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<(String, String)>,
+    ) -> Result<HttpResponse, ApiError> {
+        let (kind, value) = path.into_inner();
+        let search = Self::parse_search(&kind, &value).map_err(ApiError::InvalidQuery)?;
+
+        data.get_ref().with_chain(|block_chain| {
+            let missing = match block_chain.search_block(search) {
+                BlockSearchResult::Success(block) => {
+                    return Ok(HttpResponse::Ok().json(SearchResponse {
+                        hash: hex::encode(block.hash()),
+                        block,
+                    }));
+                }
+                BlockSearchResult::FailOfEmptyBlocks => "any block".to_string(),
+                BlockSearchResult::FailOfIndex(index) => format!("block {}", index),
+                BlockSearchResult::FailOfPreviousHash(previous_hash) => {
+                    format!("block after {}", hex::encode(previous_hash))
+                }
+                BlockSearchResult::FailOfBlockHash(hash) => format!("block {}", hex::encode(hash)),
+                BlockSearchResult::FailOfTimeStamp(time_stamp) => {
+                    format!("block with timestamp {}", time_stamp)
+                }
+                BlockSearchResult::FailOfTransaction(transaction) => {
+                    format!("block holding transaction {}", hex::encode(transaction))
+                }
+                BlockSearchResult::FailOfNonce(nonce) => format!("block with nonce {}", nonce),
+            };
+            Err(ApiError::NotFound(missing))
+        })?
+    }
+
+    fn parse_search(kind: &str, value: &str) -> Result<BlockSearch, String> {
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid {}: {}", kind, e);
+        let decode_hex = || hex::decode(value).map_err(|e| invalid(&e));
+        let search = match kind {
+            "index" => BlockSearch::SearchByIndex(value.parse().map_err(|e| invalid(&e))?),
+            "previous-hash" => BlockSearch::SearchByPreviousHash(decode_hex()?),
+            "hash" => BlockSearch::SearchByBlockHash(decode_hex()?),
+            "timestamp" => BlockSearch::SearchByTimeStamp(value.parse().map_err(|e| invalid(&e))?),
+            "transaction" => BlockSearch::SearchByTransaction(decode_hex()?),
+            "nonce" => BlockSearch::SearchByNonce(value.parse().map_err(|e| invalid(&e))?),
+            _ => return Err(format!("unknown search kind {}", kind)),
+        };
+        Ok(search)
+    }
+
+    /// The network this node runs, the chain id to sign for and the consensus values
+    pub async fn get_network(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let network = data.get_ref().network;
+        let chain_id = data.get_ref().with_chain(|block_chain| block_chain.chain_id())?;
+        Ok(HttpResponse::Ok().json(NetworkResponse {
+            network,
+            chain_id: hex::encode(chain_id),
+            params: network.params(),
+        }))
+    }
+
+    pub async fn consensus(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
+        let api_server = data.get_ref();
+        api_server.find_neighbours().await;
+        let replaced = api_server.resolve_conflicts().await;
+
+        let length = api_server.with_chain(|block_chain| block_chain.chain.len())?;
+        let neighbours = lock(&api_server.neighbour).clone();
+
+        Ok(HttpResponse::Ok().json(ConsensusResponse {
+            replaced,
+            length,
+            neighbours,
+        }))
+    }
+
+    pub async fn receive_transaction(
+        data: web::Data<Arc<ApiServer>>,
+        transaction: web::Json<WalletTransaction>,
+    ) -> Result<HttpResponse, ApiError> {
+        let tx = transaction.into_inner();
+        let api_server = data.get_ref();
+        let id = BlockchainTransaction::from_wallet_transaction(&tx).hash();
+        if !api_server.mark_seen(id.clone()) {
+            return Ok(HttpResponse::Ok().json("transaction already seen"));
+        }
+
+        // gossip is not ordered, a transaction may come before the one with the previous
+        // nonce and has to be accepted when another neighbour relays it again later
+        let added = api_server
+            .with_chain(|block_chain| block_chain.add_transaction(&tx))
+            .and_then(|added| added.map_err(ApiError::from));
+        if let Err(e) = added {
+            api_server.forget_seen(&id);
+            return Err(e);
+        }
+
+        api_server.broadcast("/gossip/transaction", &tx);
+        Ok(HttpResponse::Ok().json("transaction accepted"))
+    }
+
+    pub async fn receive_block(
+        data: web::Data<Arc<ApiServer>>,
+        block: web::Json<Block>,
+    ) -> Result<HttpResponse, ApiError> {
+        let block = block.into_inner();
+        let api_server = data.get_ref();
+        if !api_server.mark_seen(block.hash()) {
+            return Ok(HttpResponse::Ok().json("block already seen"));
+        }
+
+        let (appended, extends_tip) = api_server.with_chain(|block_chain| {
+            let extends_tip = block.previous_hash() == block_chain.last_block().hash();
+            block_chain.add_block(block.clone()).map(|appended| (appended, extends_tip))
+        })?.inspect_err(|e| warn!("failed to store received block: {}", e))?;
+
+        if !appended {
+            // a block that does not build on our tip means a neighbour is ahead of us
+            if !extends_tip {
+                let api = Arc::clone(api_server);
+                tokio::spawn(async move {
+                    api.resolve_conflicts().await;
+                });
+                return Ok(HttpResponse::Accepted().json("block does not extend the chain, syncing"));
+            }
+            return Err(ApiError::InvalidBlock);
+        }
+
+        // whatever we were mining now builds on a stale tip
+        api_server.miner.cancel();
+        api_server.broadcast("/gossip/block", &block);
+        Ok(HttpResponse::Ok().json("block accepted"))
+    }
+
+    /// Remember a gossip message id, returns false if it was already seen
+    fn mark_seen(&self, id: Vec<u8>) -> bool {
+        let mut seen = lock(&self.seen);
+        if seen.len() >= Self::SEEN_CAPACITY {
+            seen.clear();
+        }
+        seen.insert(id)
+    }
+
+    /// Forget a gossip message id, so the message is handled again when it comes back
+    fn forget_seen(&self, id: &[u8]) {
+        lock(&self.seen).remove(id);
+    }
+
+    /// Post `message` to `path` on every neighbour without waiting for the answers
+    fn broadcast<T: Serialize>(&self, path: &str, message: &T) {
+        let body = match serde_json::to_value(message) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("failed to encode gossip message: {}", e);
+                return;
+            }
+        };
+
+        let neighbours = lock(&self.neighbour).clone();
+        for neighbour in neighbours {
+            let request = self
+                .client
+                .post(format!("http://{}{}", neighbour, path))
+                .json(&body);
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await {
+                    debug!("gossip to {} failed: {}", neighbour, e);
+                }
+            });
+        }
+    }
+
+    /*
+    probe every host/port pair in the configured ranges, a neighbour is any
+    address other than ourselves that accepts a tcp connection. Nodes listen on
+    every address, so a port answering on two loopback addresses is one node
+    and only counts once.
+     */
+    async fn find_neighbours(&self) {
+        let [a, b, c, d] = Self::HOST.octets();
+        let mut found = Vec::<String>::new();
+        let mut loopback_ports = HashSet::<u16>::new();
+
+        for ip_offset in Self::NEIGHBOUR_IP_RANGE_START..=Self::NEIGHBOUR_IP_RANGE_END {
+            let host = Ipv4Addr::new(a, b, c, d.wrapping_add(ip_offset));
+            for port_offset in Self::BLOCKCHAIN_PORT_RANGE_START..=Self::BLOCKCHAIN_PORT_RANGE_END {
+                let port = Self::BLOCKCHAIN_PORT_BASE + port_offset as u16;
+                // we listen on every loopback address, so the same port there is ourselves
+                if host.is_loopback() && (port == self.port || loopback_ports.contains(&port)) {
+                    continue;
+                }
+
+                let target = format!("{}:{}", host, port);
+                let connect = TcpStream::connect(&target);
+                if let Ok(Ok(_)) = tokio::time::timeout(Self::NEIGHBOUR_TIMEOUT, connect).await {
+                    if host.is_loopback() {
+                        loopback_ports.insert(port);
+                    }
+                    found.push(target);
+                }
+            }
+        }
+
+        debug!("neighbours of port {}: {:?}", self.port, found);
+        *lock(&self.neighbour) = found;
+    }
+
+    /*
+    heaviest chain rule: fetch the chain of every neighbour and adopt the valid
+    one with the most cumulative work if it has more work than ours
+     */
+    async fn resolve_conflicts(&self) -> bool {
+        let neighbours = lock(&self.neighbour).clone();
+
+        let mut heaviest: Option<Vec<Block>> = None;
+        for neighbour in neighbours {
+            let url = format!("http://{}/chain", neighbour);
+            let response = match self.client.get(&url).send().await {
+                Ok(response) => response.json::<ChainResponse>().await,
+                Err(e) => Err(e),
+            };
+            let chain = match response {
+                Ok(response) => response.chain,
+                Err(e) => {
+                    warn!("failed to fetch chain from {}: {}", neighbour, e);
+                    continue;
+                }
+            };
+
+            let is_heavier = heaviest
+                .as_ref()
+                .is_none_or(|best| BlockChain::chain_work(&chain) > BlockChain::chain_work(best));
+            if is_heavier && BlockChain::is_valid_chain(&chain, self.network).is_ok() {
+                heaviest = Some(chain);
+            }
+        }
+
+        let Some(chain) = heaviest else {
+            return false;
+        };
+
+        let replaced = self.with_chain(|block_chain| block_chain.replace_chain(chain));
+        match replaced {
+            Ok(Ok(replaced)) => {
+                if replaced {
+                    info!("port {}: chain replaced by one with more work", self.port);
+                    self.miner.cancel();
+                }
+                replaced
+            }
+            Ok(Err(e)) => {
+                warn!("failed to store replaced chain: {}", e);
+                false
+            }
+            Err(e) => {
+                warn!("failed to replace chain: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn sync_neighbours(self: Arc<Self>) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(Self::NEIGHBOUR_IP_SYNC_TIME as u64));
+        loop {
+            interval.tick().await;
+            self.find_neighbours().await;
+            self.resolve_conflicts().await;
+        }
+    }
+
     // Instance method to get blockchain info
 
-      async fn get_index(&self) -> HttpResponse {
-        let unlock_cache = self.cache.lock().unwrap();
-        let blockchain = unlock_cache.get("blockchain").unwrap();
-        let blocks = &blockchain.chain;
-        HttpResponse::Ok().json(blocks)
+      async fn get_index(&self) -> Result<HttpResponse, ApiError> {
+        self.with_chain(|blockchain| HttpResponse::Ok().json(&blockchain.chain))
     }
 
 
     // Handler for Actix
-    pub async fn get_index_handler(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+    pub async fn get_index_handler(data: web::Data<Arc<ApiServer>>) -> Result<HttpResponse, ApiError> {
         info!("Receiving request at '/' endpoint");
         debug!("Handler received ApiServer data: {:?}", data);
         data.get_ref().get_index().await
@@ -298,17 +1300,48 @@ return  HttpResponse::Ok().json("add transaction to blockchain ok");
         let api = Arc::new(self.clone());
         let port = self.port;
 
+        tokio::spawn(api.clone().sync_neighbours());
+
         let server = HttpServer::new(move || {
             App::new()
                 .app_data(web::Data::new(api.clone()))
+                // extractor failures answer the same JSON error body as the handlers
+                .app_data(web::JsonConfig::default().error_handler(|e, _| {
+                    ApiError::InvalidQuery(format!("invalid request body: {}", e)).into()
+                }))
+                .app_data(web::QueryConfig::default().error_handler(|e, _| {
+                    ApiError::InvalidQuery(format!("invalid query: {}", e)).into()
+                }))
+                .app_data(web::PathConfig::default().error_handler(|e, _| {
+                    ApiError::InvalidQuery(format!("invalid path: {}", e)).into()
+                }))
                 .wrap(actix_web::middleware::Logger::default())
                 .route("/", web::get().to(ApiServer::get_index_handler))
                 .route("/wallet", web::get().to(Self::get_wallet))
                 .route("/get-wallet", web::get().to(Self::get_wallet_handler))
+                .route("/wallet/derive", web::post().to(Self::derive_wallet))
               .route("/transaction", web::post().to(Self::get_transaction_handler))
+              .route("/transaction/signed", web::post().to(Self::signed_transaction_handler))
                .route("/show-transaction", web::get().to(Self::show_transaction))
                .route("/mining", web::get().to(Self::mining))
+                .route("/miner/start", web::post().to(Self::start_miner))
+                .route("/miner/stop", web::post().to(Self::stop_miner))
+                .route("/miner/status", web::get().to(Self::miner_status))
                 .route("/amount/{address}", web::get().to(Self::get_amount))
+                .route("/nonce/{address}", web::get().to(Self::get_nonce))
+                .route("/mempool", web::get().to(Self::get_mempool))
+                .route("/chain", web::get().to(Self::get_chain))
+                .route("/proof/{tx_hash}", web::get().to(Self::get_proof))
+                .route("/tx/{id}", web::get().to(Self::get_transaction_receipt))
+                .route("/search/{kind}/{value}", web::get().to(Self::search_block))
+                .route("/blocks", web::get().to(Self::get_blocks))
+                .route("/block/{height}", web::get().to(Self::get_block))
+                .route("/block/hash/{hash}", web::get().to(Self::get_block_by_hash))
+                .route("/address/{address}/transactions", web::get().to(Self::get_address_transactions))
+                .route("/consensus", web::get().to(Self::consensus))
+                .route("/network", web::get().to(Self::get_network))
+                .route("/gossip/transaction", web::post().to(Self::receive_transaction))
+                .route("/gossip/block", web::post().to(Self::receive_block))
                
         });
 
@@ -321,4 +1354,19 @@ return  HttpResponse::Ok().json("add transaction to blockchain ok");
             .await
             .expect("Error running the server");
     }
-}
\ No newline at end of file
+}
+
+/*
+a handler that panicked while holding a lock leaves it poisoned, the data is
+still consistent since every update is made under one lock, so keep serving
+ */
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("recovering a poisoned lock");
+        poisoned.into_inner()
+    })
+}
+
+fn decode_hex(value: &str, field: &'static str) -> Result<Vec<u8>, ApiError> {
+    hex::decode(value).map_err(|_| ApiError::InvalidHex { field })
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// Amount of coins held as an integer count of base units
+///
+/// One coin is `10^DECIMALS` base units. Arithmetic is checked and reports
+/// overflow instead of wrapping, and amounts are written as decimal strings
+/// (`"1.5"`) in JSON so no precision is lost to floating point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountError {
+    Overflow,
+    Negative,
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::Overflow => write!(f, "amount overflow"),
+            AmountError::Negative => write!(f, "amount would be negative"),
+            AmountError::Empty => write!(f, "amount is empty"),
+            AmountError::InvalidDigit => write!(f, "amount is not a decimal number"),
+            AmountError::TooManyDecimals => {
+                write!(f, "amount has more than {} decimal places", Amount::DECIMALS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+impl Amount {
+    /// Number of decimal places of one coin
+    pub const DECIMALS: u32 = 8;
+    const UNITS_PER_COIN: u64 = 10u64.pow(Amount::DECIMALS);
+
+    pub const ZERO: Amount = Amount(0);
+    pub const COIN: Amount = Amount(Amount::UNITS_PER_COIN);
+
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.0.checked_add(rhs.0).map(Amount).ok_or(AmountError::Overflow)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.0.checked_sub(rhs.0).map(Amount).ok_or(AmountError::Negative)
+    }
+
+    /// Add up every amount, failing on overflow
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Result<Amount, AmountError> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    /// Parse a decimal number of coins such as `"12"`, `"0.5"` or `"3.00000001"`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(AmountError::Empty);
+        }
+        if !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+            return Err(AmountError::InvalidDigit);
+        }
+        // trailing zeros carry no value, "1.500000000" is still exact
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > Amount::DECIMALS as usize {
+            return Err(AmountError::TooManyDecimals);
+        }
+
+        let mut units: u64 = 0;
+        let padding = Amount::DECIMALS as usize - fraction.len();
+        for b in whole.bytes().chain(fraction.bytes()).chain("0".repeat(padding).bytes()) {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add((b - b'0') as u64))
+                .ok_or(AmountError::Overflow)?;
+        }
+
+        Ok(Amount(units))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / Amount::UNITS_PER_COIN;
+        let fraction = self.0 % Amount::UNITS_PER_COIN;
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+
+        let fraction = format!("{:0width$}", fraction, width = Amount::DECIMALS as usize);
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<u64, AmountError> {
+        s.parse::<Amount>().map(Amount::units)
+    }
+
+    #[test]
+    fn parses_whole_and_fractional_coins() {
+        assert_eq!(parse("12"), Ok(12 * Amount::COIN.units()));
+        assert_eq!(parse("0.5"), Ok(50_000_000));
+        assert_eq!(parse(".5"), Ok(50_000_000));
+        assert_eq!(parse("3."), Ok(300_000_000));
+        assert_eq!(parse("3.00000001"), Ok(300_000_001));
+        assert_eq!(parse(" 1 "), Ok(100_000_000));
+        assert_eq!(parse("0"), Ok(0));
+    }
+
+    #[test]
+    fn trailing_zeros_do_not_count_as_decimals() {
+        assert_eq!(parse("1.500000000"), Ok(150_000_000));
+        assert_eq!(parse("1.000000010"), Ok(100_000_001));
+        assert_eq!(parse("1.000000001"), Err(AmountError::TooManyDecimals));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_decimal_number() {
+        assert_eq!(parse(""), Err(AmountError::Empty));
+        assert_eq!(parse("."), Err(AmountError::Empty));
+        assert_eq!(parse("-1"), Err(AmountError::InvalidDigit));
+        assert_eq!(parse("+1"), Err(AmountError::InvalidDigit));
+        assert_eq!(parse("1e3"), Err(AmountError::InvalidDigit));
+        assert_eq!(parse("1.2.3"), Err(AmountError::InvalidDigit));
+        assert_eq!(parse("1,5"), Err(AmountError::InvalidDigit));
+    }
+
+    #[test]
+    fn rejects_amounts_over_u64() {
+        let max = Amount::from_units(u64::MAX);
+        assert_eq!(parse(&max.to_string()), Ok(u64::MAX));
+        assert_eq!(parse("184467440737.09551616"), Err(AmountError::Overflow));
+        assert_eq!(parse("99999999999999999999"), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for units in [0, 1, 10, 50_000_000, 100_000_000, 123_456_789, u64::MAX] {
+            let amount = Amount::from_units(units);
+            assert_eq!(amount.to_string().parse(), Ok(amount));
+        }
+        assert_eq!(Amount::from_units(150_000_000).to_string(), "1.5");
+        assert_eq!(Amount::from_units(1).to_string(), "0.00000001");
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        assert_eq!(
+            Amount::from_units(u64::MAX).checked_add(Amount::from_units(1)),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_units(1)), Err(AmountError::Negative));
+        assert_eq!(
+            Amount::checked_sum([Amount::COIN, Amount::COIN]),
+            Ok(Amount::from_units(2 * Amount::COIN.units()))
+        );
+    }
+}
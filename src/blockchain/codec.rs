@@ -0,0 +1,334 @@
+use std::fmt;
+
+/*
+Binary wire format shared by transactions and blocks.
+
+Every encoding starts with a version byte. Variable length fields are written
+as [length: u32 big endian][bytes] and integers as fixed width big endian.
+Decoding never trusts a length: each field has a maximum, and running out of
+bytes, leftover bytes or an unknown version is an error instead of a panic.
+
+Version 0 is not written anymore. It is the unversioned transaction format of
+the first nodes: sender, recipient and the value in coins as an f64, each with a
+u64 length prefix. Since the first prefix is far below 2^56 its first byte is
+always 0, which is how it is told apart from the versioned formats.
+*/
+pub const LEGACY_VERSION: u8 = 0;
+pub const VERSION: u8 = 1;
+
+/// Longest address, sender and recipient alike
+pub const MAX_ADDRESS_LEN: usize = 64;
+/// Longest hex encoded public key or signature
+pub const MAX_KEY_LEN: usize = 256;
+/// Longest hash a block may point back to
+pub const MAX_HASH_LEN: usize = 64;
+/// Largest serialized transaction inside a block
+pub const MAX_TRANSACTION_LEN: usize = 4 * 1024;
+/// Most transactions a block may hold
+pub const MAX_BLOCK_TRANSACTIONS: usize = 4096;
+
+/// Reason bytes could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecError {
+    Truncated,
+    UnsupportedVersion(u8),
+    TooLong { field: &'static str, len: u64, max: usize },
+    InvalidLength { field: &'static str, len: u64 },
+    InvalidValue { field: &'static str },
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated => write!(f, "unexpected end of data"),
+            CodecError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            CodecError::TooLong { field, len, max } => {
+                write!(f, "{} is {} bytes long, at most {} allowed", field, len, max)
+            }
+            CodecError::InvalidLength { field, len } => {
+                write!(f, "{} cannot be {} bytes long", field, len)
+            }
+            CodecError::InvalidValue { field } => write!(f, "{} holds an invalid value", field),
+            CodecError::TrailingBytes(n) => write!(f, "{} unexpected trailing bytes", n),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Builds an encoding field by field
+pub struct Writer {
+    bin: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(version: u8) -> Self {
+        Writer { bin: vec![version] }
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bin.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bin.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u128(&mut self, value: u128) -> &mut Self {
+        self.bin.extend(value.to_be_bytes());
+        self
+    }
+
+    /// Length prefixed bytes, callers keep fields under their decode limit
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.bin.extend(value);
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bin)
+    }
+}
+
+/// Reads an encoding field by field, checking every length
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        let end = self.pos.checked_add(len).ok_or(CodecError::Truncated)?;
+        let field = self.bytes.get(self.pos..end).ok_or(CodecError::Truncated)?;
+        self.pos = end;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        let field = self.take(N)?;
+        Ok(field.try_into().expect("take returns exactly N bytes"))
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, CodecError> {
+        Ok(u128::from_be_bytes(self.array()?))
+    }
+
+    /// Length prefixed bytes of at most `max` bytes
+    pub fn bytes(&mut self, field: &'static str, max: usize) -> Result<Vec<u8>, CodecError> {
+        let len = self.u32()?;
+        self.sized(field, len as u64, max)
+    }
+
+    /// `len` bytes, refused before reading if `len` is over `max`
+    pub fn sized(&mut self, field: &'static str, len: u64, max: usize) -> Result<Vec<u8>, CodecError> {
+        if len > max as u64 {
+            return Err(CodecError::TooLong { field, len, max });
+        }
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    /// Fail unless every byte was consumed
+    pub fn finish(&self) -> Result<(), CodecError> {
+        match self.bytes.len() - self.pos {
+            0 => Ok(()),
+            n => Err(CodecError::TrailingBytes(n)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::amount::Amount;
+    use crate::blockchain::network::Network;
+    use crate::blockchain::transaction::Transaction;
+    use crate::blockchain::{Block, Serialization};
+
+    fn transaction() -> Transaction {
+        let mut tx = Transaction::new(b"sender".to_vec(), b"recipient".to_vec(), Amount::COIN);
+        tx.fee = Amount::from_units(7);
+        tx.nonce = 3;
+        tx.public_key = b"public key".to_vec();
+        tx.signature = b"signature".to_vec();
+        tx
+    }
+
+    fn block() -> Block {
+        let mut block = Network::Devnet.genesis();
+        block.nonce = u64::MAX;
+        block.difficulty = 5;
+        block.transactions = vec![transaction().serialization(), transaction().serialization()];
+        block
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = transaction().serialization();
+        let decoded = Transaction::deserialization(&bytes).unwrap();
+        assert_eq!(decoded.serialization(), bytes);
+
+        let bytes = block().serialization();
+        let decoded = Block::deserialization(&bytes).unwrap();
+        assert_eq!(decoded.serialization(), bytes);
+        assert_eq!(decoded.nonce(), u64::MAX);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = transaction().serialization();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Transaction::deserialization(&bytes[..len]).err(),
+                Some(CodecError::Truncated),
+                "transaction cut at {}",
+                len
+            );
+        }
+
+        let bytes = block().serialization();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Block::deserialization(&bytes[..len]).err(),
+                Some(CodecError::Truncated),
+                "block cut at {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = transaction().serialization();
+        bytes.extend([0, 0]);
+        assert_eq!(Transaction::deserialization(&bytes).err(), Some(CodecError::TrailingBytes(2)));
+
+        let mut bytes = block().serialization();
+        bytes.push(0);
+        assert_eq!(Block::deserialization(&bytes).err(), Some(CodecError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn rejects_oversized_fields_before_reading_them() {
+        // a length of 4 GiB followed by nothing must fail on the length, not allocate
+        let bytes = Writer::new(VERSION).u32(u32::MAX).finish();
+        assert_eq!(
+            Transaction::deserialization(&bytes).err(),
+            Some(CodecError::TooLong {
+                field: "sender address",
+                len: u32::MAX as u64,
+                max: MAX_ADDRESS_LEN,
+            })
+        );
+
+        let mut block = block();
+        block.transactions = vec![vec![0; MAX_TRANSACTION_LEN + 1]];
+        assert_eq!(
+            Block::deserialization(&block.serialization()).err(),
+            Some(CodecError::TooLong {
+                field: "transaction",
+                len: MAX_TRANSACTION_LEN as u64 + 1,
+                max: MAX_TRANSACTION_LEN,
+            })
+        );
+
+        let bytes = Writer::new(VERSION)
+            .u64(0)
+            .bytes(&[])
+            .u128(0)
+            .u32(0)
+            .u32(MAX_BLOCK_TRANSACTIONS as u32 + 1)
+            .finish();
+        assert!(matches!(
+            Block::deserialization(&bytes),
+            Err(CodecError::TooLong { field: "transaction list", .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut bytes = transaction().serialization();
+        bytes[0] = 9;
+        assert_eq!(Transaction::deserialization(&bytes).err(), Some(CodecError::UnsupportedVersion(9)));
+
+        let mut bytes = block().serialization();
+        bytes[0] = LEGACY_VERSION;
+        assert_eq!(
+            Block::deserialization(&bytes).err(),
+            Some(CodecError::UnsupportedVersion(LEGACY_VERSION))
+        );
+    }
+
+    #[test]
+    fn reads_legacy_transactions() {
+        let field = |bin: &mut Vec<u8>, value: &[u8]| {
+            bin.extend((value.len() as u64).to_be_bytes());
+            bin.extend(value);
+        };
+        let legacy = |value: f64| {
+            let mut bin = Vec::<u8>::new();
+            field(&mut bin, b"sender");
+            field(&mut bin, b"recipient");
+            field(&mut bin, &value.to_be_bytes());
+            bin
+        };
+
+        let tx = Transaction::deserialization(&legacy(1.0)).unwrap();
+        assert_eq!(tx.sender_address, b"sender");
+        assert_eq!(tx.recipient_address, b"recipient");
+        assert_eq!(tx.value, Amount::COIN);
+        assert_eq!((tx.fee, tx.nonce), (Amount::ZERO, 0));
+        assert!(tx.public_key.is_empty() && tx.signature.is_empty());
+
+        // coins are read as the decimal the float prints as, not its nearest base unit
+        let tx = Transaction::deserialization(&legacy(0.1)).unwrap();
+        assert_eq!(tx.value, "0.1".parse().unwrap());
+        let tx = Transaction::deserialization(&legacy(2.00000001)).unwrap();
+        assert_eq!(tx.value, Amount::from_units(200_000_001));
+
+        for value in [-1.0, f64::NAN, f64::INFINITY, 1e30, 1e-9] {
+            assert_eq!(
+                Transaction::deserialization(&legacy(value)).err(),
+                Some(CodecError::InvalidValue { field: "value" }),
+                "value {}",
+                value
+            );
+        }
+
+        // the value is always an 8 byte float
+        let mut bad = Vec::<u8>::new();
+        field(&mut bad, b"sender");
+        field(&mut bad, b"recipient");
+        field(&mut bad, &[1, 2, 3]);
+        assert_eq!(
+            Transaction::deserialization(&bad).err(),
+            Some(CodecError::InvalidLength { field: "value", len: 3 })
+        );
+
+        // nothing follows the value
+        let mut long = legacy(1.0);
+        long.extend([0; 8]);
+        assert_eq!(Transaction::deserialization(&long).err(), Some(CodecError::TrailingBytes(8)));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::blockchain::Serialization;
+use crate::blockchain::transaction::Transaction;
+use crate::blockchain::{Block, BlockChain};
+use std::collections::HashMap;
+
+/// Lookup tables from block and transaction fields to block height
+///
+/// Kept in step with the chain by `BlockChain`, so searches do not have to
+/// walk every block.
+#[derive(Debug, Clone, Default)]
+pub struct ChainIndex {
+    by_hash: HashMap<Vec<u8>, usize>,
+    by_transaction: HashMap<Vec<u8>, usize>,
+    by_timestamp: HashMap<u128, usize>,
+    by_nonce: HashMap<u64, usize>,
+    // heights of the confirmed transactions of every sender, its length is the next nonce
+    sent: HashMap<Vec<u8>, Vec<usize>>,
+    // heights of the blocks holding a transaction from or to every address, once per block
+    involving: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+impl ChainIndex {
+    /// Build the index of a whole chain
+    pub fn build(chain: &[Block]) -> Self {
+        let mut index = ChainIndex::default();
+        for (height, block) in chain.iter().enumerate() {
+            index.insert(height, block);
+        }
+        index
+    }
+
+    /// Index the block at `height`
+    pub fn insert(&mut self, height: usize, block: &Block) {
+        self.by_hash.insert(block.hash(), height);
+        // the first block holding a transaction is the one that confirmed it
+        for tx_hash in block.transaction_hashes() {
+            self.by_transaction.entry(tx_hash).or_insert(height);
+        }
+        self.by_timestamp.entry(block.time_stamps).or_insert(height);
+        // nonces are not unique, the first block that used one is kept
+        self.by_nonce.entry(block.nonce).or_insert(height);
+
+        for tx in &block.transactions {
+            let Ok(tx) = Transaction::deserialization(tx) else {
+                continue;
+            };
+            for address in [&tx.sender_address, &tx.recipient_address] {
+                let heights = self.involving.entry(address.clone()).or_default();
+                if heights.last() != Some(&height) {
+                    heights.push(height);
+                }
+            }
+            if tx.sender_address != BlockChain::MINING_SENDER.as_bytes() {
+                self.sent.entry(tx.sender_address).or_default().push(height);
+            }
+        }
+    }
+
+    /// Forget every block from `height` onwards
+    pub fn truncate(&mut self, height: usize) {
+        self.by_hash.retain(|_, h| *h < height);
+        self.by_transaction.retain(|_, h| *h < height);
+        self.by_timestamp.retain(|_, h| *h < height);
+        self.by_nonce.retain(|_, h| *h < height);
+        for heights in self.sent.values_mut() {
+            heights.retain(|h| *h < height);
+        }
+        self.sent.retain(|_, heights| !heights.is_empty());
+        for heights in self.involving.values_mut() {
+            heights.retain(|h| *h < height);
+        }
+        self.involving.retain(|_, heights| !heights.is_empty());
+    }
+
+    /// Nonce the next confirmed transaction of `sender` must carry
+    pub fn next_nonce(&self, sender: &[u8]) -> u64 {
+        self.sent.get(sender).map_or(0, |heights| heights.len() as u64)
+    }
+
+    /// Heights of the blocks with a transaction from or to `address`, in chain order
+    pub fn heights_involving(&self, address: &[u8]) -> &[usize] {
+        self.involving.get(address).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn height_of_hash(&self, hash: &[u8]) -> Option<usize> {
+        self.by_hash.get(hash).copied()
+    }
+
+    pub fn height_of_transaction(&self, tx_hash: &[u8]) -> Option<usize> {
+        self.by_transaction.get(tx_hash).copied()
+    }
+
+    pub fn height_of_timestamp(&self, time_stamp: u128) -> Option<usize> {
+        self.by_timestamp.get(&time_stamp).copied()
+    }
+
+    pub fn height_of_nonce(&self, nonce: u64) -> Option<usize> {
+        self.by_nonce.get(&nonce).copied()
+    }
+}
@@ -0,0 +1,332 @@
+use crate::blockchain::Serialization;
+use crate::blockchain::amount::Amount;
+use crate::blockchain::transaction::Transaction;
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+/// A transaction waiting in the pool
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub bytes: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub transaction: Transaction,
+    // seconds since the unix epoch
+    pub added_at: u64,
+}
+
+impl MempoolEntry {
+    pub fn new(transaction: Transaction) -> Self {
+        MempoolEntry {
+            bytes: transaction.serialization(),
+            hash: transaction.hash(),
+            transaction,
+            added_at: now_secs(),
+        }
+    }
+
+    /// Compare fee per byte without dividing
+    fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        let rate = self.transaction.fee.units() as u128 * other.bytes.len() as u128;
+        let other_rate = other.transaction.fee.units() as u128 * self.bytes.len() as u128;
+        rate.cmp(&other_rate)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MempoolError {
+    Duplicate,
+    SenderLimit,
+    Full,
+}
+
+/// Numbers reported at `/mempool`
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub senders: usize,
+    pub max_per_sender: usize,
+    pub ttl_secs: u64,
+    pub total_fees: Amount,
+}
+
+/// Pool of pending transactions, kept in arrival order
+///
+/// The pool is bounded in bytes and in transactions per sender. Entries
+/// expire after `TTL`, and when the pool is full the entry paying the lowest
+/// fee per byte makes room for a better paying one.
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    entries: Vec<MempoolEntry>,
+    hashes: HashSet<Vec<u8>>,
+    per_sender: HashMap<Vec<u8>, usize>,
+    bytes: usize,
+}
+
+impl Mempool {
+    const MAX_BYTES: usize = 1024 * 1024;
+    const MAX_PER_SENDER: usize = 16;
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    /// Pool holding transactions reloaded from storage
+    pub fn from_transactions(transactions: Vec<Vec<u8>>) -> Self {
+        let mut pool = Mempool::default();
+        for bytes in transactions {
+            if let Ok(tx) = Transaction::deserialization(&bytes) {
+                let _ = pool.insert(MempoolEntry::new(tx));
+            }
+        }
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn entries(&self) -> &[MempoolEntry] {
+        &self.entries
+    }
+
+    /// Serialized transactions in arrival order
+    pub fn transactions(&self) -> Vec<Vec<u8>> {
+        self.entries.iter().map(|entry| entry.bytes.clone()).collect()
+    }
+
+    /// Add an entry, evicting lower paying ones if the pool is full
+    pub fn insert(&mut self, entry: MempoolEntry) -> Result<(), MempoolError> {
+        if self.contains(&entry.hash) {
+            return Err(MempoolError::Duplicate);
+        }
+        let sender = &entry.transaction.sender_address;
+        if self.per_sender.get(sender).copied().unwrap_or(0) >= Mempool::MAX_PER_SENDER {
+            return Err(MempoolError::SenderLimit);
+        }
+
+        // only the last pending transaction of a sender can go without leaving a nonce gap,
+        // and never one of the new entry's own sender, which it follows
+        let mut evict = HashSet::<Vec<u8>>::new();
+        let mut freed = 0;
+        while self.bytes - freed + entry.bytes.len() > Mempool::MAX_BYTES {
+            let victim = self
+                .entries
+                .iter()
+                .filter(|e| {
+                    e.transaction.sender_address != *sender
+                        && !evict.contains(&e.hash)
+                        && self.is_last_of_sender(e, &evict)
+                })
+                .min_by(|a, b| a.cmp_fee_rate(b));
+            match victim {
+                Some(victim) if victim.cmp_fee_rate(&entry) == Ordering::Less => {
+                    freed += victim.bytes.len();
+                    evict.insert(victim.hash.clone());
+                }
+                _ => return Err(MempoolError::Full),
+            }
+        }
+        self.retain(|e| !evict.contains(&e.hash));
+
+        self.hashes.insert(entry.hash.clone());
+        *self.per_sender.entry(sender.clone()).or_default() += 1;
+        self.bytes += entry.bytes.len();
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn is_last_of_sender(&self, entry: &MempoolEntry, evicted: &HashSet<Vec<u8>>) -> bool {
+        !self.entries.iter().any(|e| {
+            e.transaction.sender_address == entry.transaction.sender_address
+                && e.transaction.nonce > entry.transaction.nonce
+                && !evicted.contains(&e.hash)
+        })
+    }
+
+    /// Keep only the entries for which `keep` returns true
+    pub fn retain<F: FnMut(&MempoolEntry) -> bool>(&mut self, mut keep: F) {
+        let mut removed = Vec::<MempoolEntry>::new();
+        let mut kept = Vec::<MempoolEntry>::with_capacity(self.entries.len());
+        for entry in std::mem::take(&mut self.entries) {
+            if keep(&entry) {
+                kept.push(entry);
+            } else {
+                removed.push(entry);
+            }
+        }
+        self.entries = kept;
+
+        for entry in removed {
+            self.hashes.remove(&entry.hash);
+            self.bytes -= entry.bytes.len();
+            let sender = &entry.transaction.sender_address;
+            if let Some(count) = self.per_sender.get_mut(sender) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_sender.remove(sender);
+                }
+            }
+        }
+    }
+
+    /// Drop entries older than the TTL, with every later transaction of the same sender
+    pub fn expire(&mut self) -> usize {
+        let deadline = now_secs().saturating_sub(Mempool::TTL.as_secs());
+        let mut first_expired = HashMap::<Vec<u8>, u64>::new();
+        for entry in self.entries.iter().filter(|e| e.added_at < deadline) {
+            let nonce = first_expired
+                .entry(entry.transaction.sender_address.clone())
+                .or_insert(entry.transaction.nonce);
+            *nonce = (*nonce).min(entry.transaction.nonce);
+        }
+
+        let before = self.len();
+        self.retain(|e| {
+            first_expired
+                .get(&e.transaction.sender_address)
+                .is_none_or(|nonce| e.transaction.nonce < *nonce)
+        });
+        before - self.len()
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            count: self.len(),
+            bytes: self.bytes,
+            max_bytes: Mempool::MAX_BYTES,
+            senders: self.per_sender.len(),
+            max_per_sender: Mempool::MAX_PER_SENDER,
+            ttl_secs: Mempool::TTL.as_secs(),
+            total_fees: Amount::checked_sum(self.entries.iter().map(|e| e.transaction.fee))
+                .unwrap_or(Amount::from_units(u64::MAX)),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a quarter of the pool, so four entries fill it
+    const LARGE: usize = Mempool::MAX_BYTES / 4 - 100;
+
+    fn entry(sender: u8, nonce: u64, fee: u64, padding: usize) -> MempoolEntry {
+        let mut tx = Transaction::new(vec![sender], vec![0xff], Amount::COIN);
+        tx.nonce = nonce;
+        tx.fee = Amount::from_units(fee);
+        // the pool does not check signatures, a long one gives the entry its size
+        tx.signature = vec![0; padding];
+        MempoolEntry::new(tx)
+    }
+
+    fn full_pool(entries: Vec<MempoolEntry>) -> Mempool {
+        let mut pool = Mempool::default();
+        for entry in entries {
+            pool.insert(entry).expect("pool has room");
+        }
+        pool
+    }
+
+    #[test]
+    fn refuses_duplicates_and_busy_senders() {
+        let mut pool = Mempool::default();
+        pool.insert(entry(1, 0, 1, 0)).unwrap();
+        assert_eq!(pool.insert(entry(1, 0, 1, 0)), Err(MempoolError::Duplicate));
+
+        for nonce in 1..Mempool::MAX_PER_SENDER as u64 {
+            pool.insert(entry(1, nonce, 1, 0)).unwrap();
+        }
+        assert_eq!(
+            pool.insert(entry(1, Mempool::MAX_PER_SENDER as u64, 1, 0)),
+            Err(MempoolError::SenderLimit)
+        );
+        assert_eq!(pool.stats().senders, 1);
+    }
+
+    #[test]
+    fn full_pool_evicts_the_lowest_fee_rate() {
+        let mut pool = full_pool((1..=4).map(|sender| entry(sender, 0, sender as u64, LARGE)).collect());
+
+        let better = entry(5, 0, 5, LARGE);
+        let evicted = pool.entries()[0].hash.clone();
+        pool.insert(better.clone()).unwrap();
+        assert!(pool.contains(&better.hash));
+        assert!(!pool.contains(&evicted));
+        assert_eq!(pool.len(), 4);
+        assert!(pool.stats().bytes <= Mempool::MAX_BYTES);
+
+        // paying no more than the cheapest entry does not replace it
+        assert_eq!(pool.insert(entry(6, 0, 2, LARGE)), Err(MempoolError::Full));
+        assert_eq!(pool.insert(entry(6, 0, 1, LARGE)), Err(MempoolError::Full));
+        assert_eq!(pool.len(), 4);
+    }
+
+    #[test]
+    fn eviction_never_leaves_a_nonce_gap() {
+        let mut pool = full_pool(vec![
+            entry(1, 0, 1, LARGE),
+            entry(1, 1, 9, LARGE),
+            entry(2, 0, 5, LARGE),
+            entry(3, 0, 6, LARGE),
+        ]);
+        let first_of_sender_1 = pool.entries()[0].hash.clone();
+        let sender_2 = pool.entries()[2].hash.clone();
+
+        // sender 1's cheap transaction is followed by another one, so sender 2 goes instead
+        pool.insert(entry(4, 0, 7, LARGE)).unwrap();
+        assert!(pool.contains(&first_of_sender_1));
+        assert!(!pool.contains(&sender_2));
+    }
+
+    #[test]
+    fn entry_never_evicts_its_own_sender() {
+        let mut pool = full_pool((1..=4).map(|sender| entry(sender, 0, 1, LARGE)).collect());
+        let own = pool.entries()[0].hash.clone();
+
+        pool.insert(entry(1, 1, 100, LARGE)).unwrap();
+        assert!(pool.contains(&own));
+        assert_eq!(pool.len(), 4);
+    }
+
+    #[test]
+    fn expiry_drops_the_expired_entry_and_the_ones_after_it() {
+        let mut pool = full_pool(vec![
+            entry(1, 0, 1, 0),
+            entry(1, 1, 1, 0),
+            entry(1, 2, 1, 0),
+            entry(2, 0, 1, 0),
+        ]);
+        assert_eq!(pool.expire(), 0);
+
+        // nonce 2 still depends on the expired nonce 1
+        let old = now_secs() - Mempool::TTL.as_secs() - 1;
+        pool.entries[1].added_at = old;
+        assert_eq!(pool.expire(), 2);
+
+        let left: Vec<(Vec<u8>, u64)> = pool
+            .entries()
+            .iter()
+            .map(|e| (e.transaction.sender_address.clone(), e.transaction.nonce))
+            .collect();
+        assert_eq!(left, vec![(vec![1], 0), (vec![2], 0)]);
+        let bytes: usize = pool.entries().iter().map(|e| e.bytes.len()).sum();
+        assert_eq!(pool.stats().bytes, bytes);
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/*
+Merkle tree over the transaction hashes of a block.
+
+leaves are the transaction hashes (sha256 of the serialized transaction),
+an inner node is sha256(0x01 || left || right). The prefix keeps an inner
+node from ever being mistaken for a leaf. When a level has an odd number of
+nodes the last one is carried up unchanged instead of being paired with a
+copy of itself, so two different transaction lists never share a root.
+*/
+const NODE_PREFIX: u8 = 0x01;
+
+/// Root of a block without transactions
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+/// Which side of the running hash a sibling sits on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    #[serde(with = "hex::serde")]
+    pub hash: Vec<u8>,
+    pub side: Side,
+}
+
+/// Path from a leaf to the root, enough to check inclusion without the rest of the block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    #[serde(with = "hex::serde")]
+    pub leaf: Vec<u8>,
+    pub index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+fn hash_nodes(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_nodes(left, right),
+            [single] => single.clone(),
+            _ => unreachable!("chunks(2) yields one or two nodes"),
+        })
+        .collect()
+}
+
+/// Merkle root of the given leaf hashes
+pub fn merkle_root(leaves: &[Vec<u8>]) -> Vec<u8> {
+    if leaves.is_empty() {
+        return EMPTY_ROOT.to_vec();
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Inclusion proof for the leaf at `index`, `None` if there is no such leaf
+pub fn merkle_proof(leaves: &[Vec<u8>], index: usize) -> Option<MerkleProof> {
+    let leaf = leaves.get(index)?.clone();
+
+    let mut steps = Vec::<ProofStep>::new();
+    let mut level = leaves.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            let side = if sibling < position { Side::Left } else { Side::Right };
+            steps.push(ProofStep {
+                hash: hash.clone(),
+                side,
+            });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { leaf, index, steps })
+}
+
+/// Check that `proof` links its leaf to `root`
+pub fn verify_proof(proof: &MerkleProof, root: &[u8]) -> bool {
+    let computed = proof.steps.iter().fold(proof.leaf.clone(), |hash, step| match step.side {
+        Side::Left => hash_nodes(&step.hash, &hash),
+        Side::Right => hash_nodes(&hash, &step.hash),
+    });
+
+    computed == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| Sha256::digest([i]).to_vec()).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        // odd levels carry their last node up, so cover every shape up to a few levels
+        for count in 1..=17 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for index in 0..leaves.len() {
+                let proof = merkle_proof(&leaves, index).expect("leaf exists");
+                assert_eq!(proof.leaf, leaves[index]);
+                assert!(verify_proof(&proof, &root), "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_survives_json() {
+        let leaves = leaves(5);
+        let proof = merkle_proof(&leaves, 4).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: MerkleProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(verify_proof(&decoded, &merkle_root(&leaves)));
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let leaves = leaves(6);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2).unwrap();
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaf = leaves[3].clone();
+        assert!(!verify_proof(&wrong_leaf, &root));
+
+        let mut wrong_side = proof.clone();
+        wrong_side.steps[0].side = Side::Left;
+        assert!(!verify_proof(&wrong_side, &root));
+
+        let mut wrong_sibling = proof;
+        wrong_sibling.steps[1].hash[0] ^= 1;
+        assert!(!verify_proof(&wrong_sibling, &root));
+    }
+
+    #[test]
+    fn missing_leaf_has_no_proof() {
+        assert!(merkle_proof(&leaves(3), 3).is_none());
+        assert!(merkle_proof(&[], 0).is_none());
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT.to_vec());
+    }
+
+    #[test]
+    fn odd_node_is_not_paired_with_itself() {
+        // duplicating the last leaf would give the same root with Bitcoin's rule
+        let three = leaves(3);
+        let mut four = three.clone();
+        four.push(three[2].clone());
+        assert_ne!(merkle_root(&three), merkle_root(&four));
+    }
+}
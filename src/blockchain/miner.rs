@@ -0,0 +1,147 @@
+use crate::blockchain::Block;
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/*
+Proof of work runs on `threads` worker threads. Worker i tries the nonces
+i, i + threads, i + 2 * threads, ... on its own copy of the block template, so
+the threads never try the same nonce and share nothing but two flags: the one
+set by the worker that found a nonce, and the generation of the miner.
+
+Every time the chain tip changes the generation is bumped with `cancel`. A job
+remembers the generation its template was built at and gives up as soon as it
+moved on, since a block on an old tip would be rejected anyway.
+*/
+#[derive(Debug)]
+pub struct Miner {
+    threads: usize,
+    generation: AtomicU64,
+    // hashes per second of the last finished job
+    hashrate: AtomicU64,
+}
+
+/// Outcome of one mining job
+#[derive(Debug, Clone)]
+pub struct MiningReport {
+    // the solved block, `None` if the job was cancelled
+    pub block: Option<Block>,
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningReport {
+    pub fn hashrate(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.hashes as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+}
+
+impl Miner {
+    // how many nonces a worker tries between two looks at the stop flags
+    const CHECK_INTERVAL: u64 = 1024;
+
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            generation: AtomicU64::new(0),
+            hashrate: AtomicU64::new(0),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Generation to pass to `mine` for a template built now
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Stop every job started on an older tip
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Hashes per second of the last finished job
+    pub fn hashrate(&self) -> u64 {
+        self.hashrate.load(Ordering::Relaxed)
+    }
+
+    /// Search a nonce that gives `template` its difficulty, blocking until found or cancelled
+    pub fn mine(&self, template: &Block, generation: u64) -> MiningReport {
+        let merkle_root = template.merkle_root();
+        let found = AtomicBool::new(false);
+        let start = Instant::now();
+
+        let results: Vec<(Option<Block>, u64)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads as u64)
+                .map(|worker| {
+                    let merkle_root = &merkle_root;
+                    let found = &found;
+                    scope.spawn(move || {
+                        self.work(template.clone(), worker, merkle_root, found, generation)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("mining worker panicked"))
+                .collect()
+        });
+
+        let hashes = results.iter().map(|(_, hashes)| hashes).sum();
+        let report = MiningReport {
+            block: results.into_iter().find_map(|(block, _)| block),
+            hashes,
+            elapsed: start.elapsed(),
+        };
+        self.hashrate.store(report.hashrate(), Ordering::Relaxed);
+        report
+    }
+
+    fn work(
+        &self,
+        mut block: Block,
+        worker: u64,
+        merkle_root: &[u8],
+        found: &AtomicBool,
+        generation: u64,
+    ) -> (Option<Block>, u64) {
+        let step = self.threads as u64;
+        let mut hashes = 0;
+        block.nonce = block.nonce.wrapping_add(worker);
+        loop {
+            if hashes % Miner::CHECK_INTERVAL == 0
+                && (found.load(Ordering::Relaxed) || self.generation() != generation)
+            {
+                return (None, hashes);
+            }
+
+            hashes += 1;
+            if Block::hash_meets(&block.hash_with_root(merkle_root), block.difficulty) {
+                found.store(true, Ordering::Relaxed);
+                return (Some(block), hashes);
+            }
+
+            // with 64 bits the range cannot run out before the difficulty is met
+            match block.nonce.checked_add(step) {
+                Some(nonce) => block.nonce = nonce,
+                None => return (None, hashes),
+            }
+        }
+    }
+}
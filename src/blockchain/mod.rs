@@ -1,17 +1,37 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
     ops::{AddAssign, Index},
-    time::{Instant, SystemTime},
+    path::Path,
+    slice,
+    time::{Duration, SystemTime},
 };
-use crate::wallet::{Transaction as WalletTransaction, Wallet};
-use crate::blockchain::transaction::Transaction;
+use crate::wallet::{Transaction as WalletTransaction, Wallet, address::{Address, AddressError}};
+use network::Network;
+use crate::blockchain::amount::{Amount, AmountError};
+use crate::blockchain::codec::{CodecError, Reader, Writer};
+use crate::blockchain::index::ChainIndex;
+use crate::blockchain::mempool::{Mempool, MempoolEntry, MempoolError};
+use crate::blockchain::miner::Miner;
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::storage::Storage;
+use crate::blockchain::transaction::{Transaction, TransactionStatus};
+pub mod amount;
+pub mod codec;
+pub mod index;
+pub mod mempool;
+pub mod miner;
+pub mod merkle;
+pub mod network;
+pub mod storage;
 pub mod transaction;
 
-/// Trait for serialization/deserialization
+/// Trait for the versioned binary encoding, see `codec`
 pub trait Serialization<T> {
     fn serialization(&self) -> Vec<u8>;
-    fn deserialization(byte: Vec<u8>) -> T;
+    fn deserialization(bytes: &[u8]) -> Result<T, CodecError>;
 }
 
 /// Block search types
@@ -20,8 +40,9 @@ pub enum BlockSearch {
     SearchByPreviousHash(Vec<u8>),
     SearchByBlockHash(Vec<u8>),
     SearchByTimeStamp(u128),
+    // hash of the transaction
     SearchByTransaction(Vec<u8>),
-    SearchByNonce(i32),
+    SearchByNonce(u64),
 }
 
 /// Block search result
@@ -31,23 +52,190 @@ pub enum BlockSearchResult<'a> {
     FailOfIndex(usize),
     FailOfPreviousHash(Vec<u8>),
     FailOfBlockHash(Vec<u8>),
-    FailOfNonce(i32),
+    FailOfNonce(u64),
     FailOfTimeStamp(u128),
     FailOfTransaction(Vec<u8>),
 }
 
+/// Reason a chain failed validation, naming the offending block
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainError {
+    EmptyChain,
+    Genesis,
+    PreviousHash { index: usize },
+    TimeStampTooOld { index: usize },
+    TimeStampInFuture { index: usize },
+    Difficulty { index: usize },
+    ProofOfWork { index: usize },
+    MalformedTransaction { index: usize, transaction: usize },
+    InvalidSignature { index: usize, transaction: usize },
+    SenderMismatch { index: usize, transaction: usize },
+    Nonce { index: usize, transaction: usize },
+    InsufficientFunds { index: usize, transaction: usize },
+    MissingReward { index: usize },
+    DuplicateReward { index: usize },
+    InvalidReward { index: usize },
+    WrongNetwork { index: usize, transaction: usize },
+}
+
+impl ChainError {
+    /// Height of the first block that broke a rule
+    pub fn index(&self) -> usize {
+        match self {
+            ChainError::EmptyChain | ChainError::Genesis => 0,
+            ChainError::PreviousHash { index }
+            | ChainError::TimeStampTooOld { index }
+            | ChainError::TimeStampInFuture { index }
+            | ChainError::Difficulty { index }
+            | ChainError::ProofOfWork { index }
+            | ChainError::MalformedTransaction { index, .. }
+            | ChainError::InvalidSignature { index, .. }
+            | ChainError::SenderMismatch { index, .. }
+            | ChainError::WrongNetwork { index, .. }
+            | ChainError::Nonce { index, .. }
+            | ChainError::InsufficientFunds { index, .. }
+            | ChainError::MissingReward { index }
+            | ChainError::DuplicateReward { index }
+            | ChainError::InvalidReward { index } => *index,
+        }
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::EmptyChain => write!(f, "chain has no genesis block"),
+            ChainError::Genesis => write!(f, "block 0 is not the genesis block of this network"),
+            ChainError::PreviousHash { index } => {
+                write!(f, "block {}: previous hash does not match block {}", index, index - 1)
+            }
+            ChainError::TimeStampTooOld { index } => write!(
+                f,
+                "block {}: timestamp is not after the median of the previous {} blocks",
+                index,
+                BlockChain::MEDIAN_TIME_SPAN
+            ),
+            ChainError::TimeStampInFuture { index } => {
+                write!(f, "block {}: timestamp is too far in the future", index)
+            }
+            ChainError::Difficulty { index } => {
+                write!(f, "block {}: claims a difficulty other than the retarget rule gives", index)
+            }
+            ChainError::ProofOfWork { index } => {
+                write!(f, "block {}: hash does not meet its difficulty", index)
+            }
+            ChainError::MalformedTransaction { index, transaction } => {
+                write!(f, "block {}: transaction {} cannot be decoded", index, transaction)
+            }
+            ChainError::InvalidSignature { index, transaction } => {
+                write!(f, "block {}: transaction {} has an invalid signature", index, transaction)
+            }
+            ChainError::SenderMismatch { index, transaction } => write!(
+                f,
+                "block {}: transaction {} is signed by a key that does not own the sender",
+                index, transaction
+            ),
+            ChainError::Nonce { index, transaction } => {
+                write!(f, "block {}: transaction {} has an out of order nonce", index, transaction)
+            }
+            ChainError::InsufficientFunds { index, transaction } => write!(
+                f,
+                "block {}: transaction {} spends more than its sender holds",
+                index, transaction
+            ),
+            ChainError::MissingReward { index } => {
+                write!(f, "block {}: no mining reward transaction", index)
+            }
+            ChainError::DuplicateReward { index } => {
+                write!(f, "block {}: more than one mining reward transaction", index)
+            }
+            ChainError::InvalidReward { index } => {
+                write!(f, "block {}: reward is not the block reward plus the fees", index)
+            }
+            ChainError::WrongNetwork { index, transaction } => write!(
+                f,
+                "block {}: transaction {} pays or spends an address of another network",
+                index, transaction
+            ),
+        }
+    }
+}
+
+/// Reason a transaction was refused by the pool
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum TransactionError {
+    MinerSender,
+    RewardSender,
+    InvalidRecipient,
+    WrongNetwork,
+    InvalidSignature,
+    SenderMismatch,
+    InvalidAmount,
+    InvalidNonce { expected: u64, got: u64 },
+    InsufficientFunds { available: Amount, required: Amount },
+    AmountOverflow,
+    Malformed,
+    Duplicate,
+    SenderLimit,
+    PoolFull,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::MinerSender => write!(f, "miner cannot send money to himself"),
+            TransactionError::RewardSender => {
+                write!(f, "reward transactions are only created by mining")
+            }
+            TransactionError::InvalidRecipient => {
+                write!(f, "recipient is not a valid address, check it for typos")
+            }
+            TransactionError::WrongNetwork => {
+                write!(f, "transaction addresses belong to another network")
+            }
+            TransactionError::InvalidSignature => write!(f, "invalid transaction signature"),
+            TransactionError::SenderMismatch => {
+                write!(f, "public key does not belong to the sender address")
+            }
+            TransactionError::InvalidAmount => write!(f, "amount must be greater than zero"),
+            TransactionError::InvalidNonce { expected, got } => {
+                write!(f, "invalid nonce: expected {}, got {}", expected, got)
+            }
+            TransactionError::InsufficientFunds { available, required } => write!(
+                f,
+                "insufficient funds: available {}, required {}",
+                available, required
+            ),
+            TransactionError::AmountOverflow => write!(f, "balance calculation overflowed"),
+            TransactionError::Malformed => {
+                write!(f, "transaction does not fit the wire format limits")
+            }
+            TransactionError::Duplicate => write!(f, "transaction is already in the pool"),
+            TransactionError::SenderLimit => {
+                write!(f, "sender has too many transactions in the pool")
+            }
+            TransactionError::PoolFull => {
+                write!(f, "pool is full and the fee is too low to replace anything")
+            }
+        }
+    }
+}
+
 /// Blockchain block
 #[derive(Debug ,Clone ,Serialize ,Deserialize)]
 pub struct Block {
-    nonce: i32,
+    nonce: u64,
     previous_hash: Vec<u8>,
     time_stamps: u128,
+    // leading zero bits the block hash must have
+    difficulty: u32,
     transactions: Vec<Vec<u8>>,
 }
 
 /// Allow `*block += 1` to increment nonce
-impl AddAssign<i32> for Block {
-    fn add_assign(&mut self, rhs: i32) {
+impl AddAssign<u64> for Block {
+    fn add_assign(&mut self, rhs: u64) {
         self.nonce += rhs;
     }
 }
@@ -61,7 +249,7 @@ impl PartialEq for Block {
 
 impl Block {
     /// Create a new block
-    pub fn new(nonce: i32, previous_hash: Vec<u8>) -> Self {
+    pub fn new(nonce: u64, previous_hash: Vec<u8>) -> Self {
         let time_now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
@@ -69,6 +257,7 @@ impl Block {
             nonce,
             previous_hash,
             time_stamps: time_now.as_nanos(),
+            difficulty: 0,
             transactions: Vec::<Vec<u8>>::new(),
         }
     }
@@ -79,33 +268,155 @@ impl Block {
         println!("Timestamp : {:x}", self.time_stamps);
         println!("previous hash : {:?}", self.previous_hash);
         for (idx, tx) in self.transactions.iter().enumerate() {
-            let transaction = Transaction::deserialization(tx.clone());
-            println!("the {}th transaction is: {}", idx, transaction);
+            match Transaction::deserialization(tx) {
+                Ok(transaction) => println!("the {}th transaction is: {}", idx, transaction),
+                Err(e) => println!("the {}th transaction is malformed: {}", idx, e),
+            }
         }
     }
 
-    /// Compute SHA256 hash of block
-    pub fn hash(&self) -> Vec<u8> {
-        let mut bin = Vec::<u8>::new();
-        bin.extend(self.nonce.to_be_bytes());
-        bin.extend(self.previous_hash.clone());
-        bin.extend(self.time_stamps.to_be_bytes());
-        for tx in &self.transactions {
-            bin.extend(tx.clone());
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn previous_hash(&self) -> &[u8] {
+        &self.previous_hash
+    }
+
+    pub fn time_stamp(&self) -> u128 {
+        self.time_stamps
+    }
+
+    /// Serialized transactions, the reward first
+    pub fn transactions(&self) -> &[Vec<u8>] {
+        &self.transactions
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    /// Expected number of hashes needed to mine this block
+    ///
+    /// Received chains are compared by work before they are validated, so a
+    /// difficulty above the maximum only counts as the maximum instead of
+    /// overflowing the shift.
+    pub fn work(&self) -> u128 {
+        1u128
+            .checked_shl(self.difficulty.min(BlockChain::MAX_DIFFICULTY))
+            .unwrap_or(u128::MAX)
+    }
+
+    /// Whether the block hash has at least `difficulty` leading zero bits
+    pub fn meets_difficulty(&self) -> bool {
+        Block::hash_meets(&self.hash(), self.difficulty)
+    }
+
+    fn hash_meets(hash: &[u8], difficulty: u32) -> bool {
+        let mut zero_bits = 0;
+        for byte in hash {
+            zero_bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
         }
+        zero_bits >= difficulty
+    }
+
+    /// Hashes of the block's transactions, the leaves of its merkle tree
+    pub fn transaction_hashes(&self) -> Vec<Vec<u8>> {
+        self.transactions
+            .iter()
+            .map(|tx| Sha256::digest(tx).to_vec())
+            .collect()
+    }
 
+    /// Merkle root over the block's transactions
+    pub fn merkle_root(&self) -> Vec<u8> {
+        merkle::merkle_root(&self.transaction_hashes())
+    }
+
+    /// Proof that the transaction with hash `tx_hash` is part of this block
+    pub fn merkle_proof(&self, tx_hash: &[u8]) -> Option<MerkleProof> {
+        let leaves = self.transaction_hashes();
+        let index = leaves.iter().position(|leaf| leaf == tx_hash)?;
+        merkle::merkle_proof(&leaves, index)
+    }
+
+    /// Compute SHA256 hash of block, transactions are committed through their merkle root
+    pub fn hash(&self) -> Vec<u8> {
+        self.hash_with_root(&self.merkle_root())
+    }
+
+    /// Block hash for an already computed merkle root, the miner hashes the same root many times
+    fn hash_with_root(&self, merkle_root: &[u8]) -> Vec<u8> {
         let mut hasher = Sha256::new();
-        hasher.update(bin);
+        hasher.update(self.nonce.to_be_bytes());
+        hasher.update(&self.previous_hash);
+        hasher.update(self.time_stamps.to_be_bytes());
+        hasher.update(self.difficulty.to_be_bytes());
+        hasher.update(merkle_root);
         hasher.finalize().to_vec()
     }
 }
 
+impl Serialization<Block> for Block {
+    fn serialization(&self) -> Vec<u8> {
+        let mut writer = Writer::new(codec::VERSION);
+        writer
+            .u64(self.nonce)
+            .bytes(&self.previous_hash)
+            .u128(self.time_stamps)
+            .u32(self.difficulty)
+            .u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            writer.bytes(tx);
+        }
+        writer.finish()
+    }
+
+    fn deserialization(bytes: &[u8]) -> Result<Block, CodecError> {
+        let mut reader = Reader::new(bytes);
+        match reader.u8()? {
+            codec::VERSION => {}
+            version => return Err(CodecError::UnsupportedVersion(version)),
+        }
+        let nonce = reader.u64()?;
+        let previous_hash = reader.bytes("previous hash", codec::MAX_HASH_LEN)?;
+        let time_stamps = reader.u128()?;
+        let difficulty = reader.u32()?;
+        let count = reader.u32()?;
+        if count as usize > codec::MAX_BLOCK_TRANSACTIONS {
+            return Err(CodecError::TooLong {
+                field: "transaction list",
+                len: count as u64,
+                max: codec::MAX_BLOCK_TRANSACTIONS,
+            });
+        }
+        let transactions = (0..count)
+            .map(|_| reader.bytes("transaction", codec::MAX_TRANSACTION_LEN))
+            .collect::<Result<Vec<Vec<u8>>, CodecError>>()?;
+        reader.finish()?;
+
+        Ok(Block {
+            nonce,
+            previous_hash,
+            time_stamps,
+            difficulty,
+            transactions,
+        })
+    }
+}
+
 /// Blockchain struct
 #[derive(Debug ,Clone)]
 pub struct BlockChain {
-   pub transaction_pool: Vec<Vec<u8>>,
+   pub transaction_pool: Mempool,
    pub chain: Vec<Block>,
   pub  blockhain_address: String,
+   storage: Option<Storage>,
+   index: ChainIndex,
+   network: Network,
 }
 
 /// Allow indexing into blockchain to get a block
@@ -118,26 +429,98 @@ impl Index<usize> for BlockChain {
 }
 
 impl BlockChain {
-    const DIFFICULTY: usize = 3;
+    // 12 bits is the old fixed difficulty of 3 leading zero hex digits
+    // the initial and minimum difficulty, block time and reward come from the network
+    const MAX_DIFFICULTY: u32 = 64;
+    // retarget every RETARGET_INTERVAL blocks towards one block per target block time
+    const RETARGET_INTERVAL: usize = 10;
+    // a block must be younger than the median timestamp of this many blocks before it
+    const MEDIAN_TIME_SPAN: usize = 11;
+    // and no older than this ahead of our clock; Bitcoin allows two hours on ten minute
+    // blocks, here a block comes every few seconds so the drift is kept to minutes
+    const MAX_FUTURE_DRIFT: Duration = Duration::from_secs(2 * 60);
     const MINING_SENDER: &str = "THE_BLOCKCHAIN";
-    const MINING_REWARD: f64 = 1.0;
+    // room for pool transactions in a block, the reward comes on top
+    const MAX_BLOCK_TRANSACTION_BYTES: usize = 64 * 1024;
 
     /// Create new blockchain and automatically mine genesis block
-    pub fn new(address: String) -> Self {
+    pub fn new(address: String, network: Network) -> Self {
         let mut bc = BlockChain {
-            transaction_pool: Vec::<Vec<u8>>::new(),
+            transaction_pool: Mempool::default(),
             chain: Vec::<Block>::new(),
             blockhain_address: address,
+            storage: None,
+            index: ChainIndex::default(),
+            network,
+        };
+
+        // in-memory chain, nothing can fail to write
+        bc.create_genesis().expect("in-memory genesis cannot fail");
+
+        bc
+    }
+
+    /// Open the blockchain stored in `dir`, creating the genesis block if it is empty
+    pub fn open<P: AsRef<Path>>(address: String, dir: P, network: Network) -> io::Result<Self> {
+        let (storage, chain, transaction_pool) = Storage::open(dir)?;
+        let mut bc = BlockChain {
+            // stored transactions start a fresh TTL
+            transaction_pool: Mempool::from_transactions(transaction_pool),
+            index: ChainIndex::build(&chain),
+            chain,
+            blockhain_address: address,
+            storage: Some(storage),
+            network,
         };
 
+        // keep the valid prefix of whatever was stored
+        if let Err(e) = BlockChain::is_valid_chain(&bc.chain, network) {
+            let height = e.index();
+            if !bc.chain.is_empty() {
+                log::warn!("stored chain is invalid ({}), truncating to {} block(s)", e, height);
+            }
+            if let Some(storage) = bc.storage.as_mut() {
+                storage.truncate(height)?;
+            }
+            bc.chain.truncate(height);
+            bc.index.truncate(height);
+        }
+
+        if bc.chain.is_empty() {
+            bc.create_genesis()?;
+        } else {
+            bc.drop_foreign_transactions();
+        }
+
+        Ok(bc)
+    }
+
+    fn create_genesis(&mut self) -> io::Result<()> {
         // create genesis block
-        let genesis_block = Block::new(0, vec![0, 32]);
-        bc.chain.push(genesis_block);
+        self.push_block(self.network.genesis())?;
+        // a reloaded pool may have been signed for the chain that was stored before
+        self.drop_foreign_transactions();
 
         // mine the genesis block
-        bc.mining();
+        self.mine_block()
+    }
 
-        bc
+    /// Persist a block, then append it to the chain
+    fn push_block(&mut self, block: Block) -> io::Result<()> {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.append_block(&block)?;
+        }
+        self.index.insert(self.chain.len(), &block);
+        self.chain.push(block);
+        Ok(())
+    }
+
+    fn save_pool(&self) {
+        if let Some(storage) = &self.storage
+            && let Err(e) = storage.save_pool(&self.transaction_pool.transactions())
+        {
+            log::error!("failed to save transaction pool: {}", e);
+        }
     }
 
     /// Print the entire blockchain
@@ -154,11 +537,13 @@ impl BlockChain {
         for (i, block) in self.chain.iter().enumerate() {
             println!("\nBlock #{} {}", i, "-".repeat(50));
             println!("Nonce       : {}", block.nonce);
+            println!("Difficulty  : {}", block.difficulty);
             println!("Timestamp   : {}", block.time_stamps);
             println!(
                 "PreviousHash: {}",
                 hex::encode(&block.previous_hash)
             );
+            println!("MerkleRoot  : {}", hex::encode(block.merkle_root()));
             println!("BlockHash   : {}", hex::encode(block.hash()));
             println!("Transactions:");
             if block.transactions.is_empty() {
@@ -173,23 +558,230 @@ impl BlockChain {
         println!("\nEnd of Blockchain\n{}", "=".repeat(80));
     }
 
-    /// Create a new block and add transactions
-    pub fn create_block(&mut self, nonce: i32, previous_hash: Vec<u8>) {
-        let mut block = Block::new(nonce, previous_hash);
-        for tx in &self.transaction_pool {
-            block.transactions.push(tx.clone());
-        }
-        self.transaction_pool.clear();
+    /// Mine a new block from the best paying transactions of the pool and the miner reward
+    pub fn create_block(&mut self, nonce: u64, previous_hash: Vec<u8>) -> io::Result<()> {
+        let template = self.assemble_block(nonce, previous_hash)?;
 
-        let now = Instant::now();
-        let proof_hash = BlockChain::do_proof_of_work(&mut block);
-        let elapsed = now.elapsed();
-        self.chain.push(block);
+        // nothing else can cancel a miner that only lives for this block
+        let miner = Miner::default();
+        let report = miner.mine(&template, miner.generation());
+        let block = report.block.clone().expect("uncancelled mining always finds a nonce");
+        let proof_hash = hex::encode(block.hash());
+
+        // the pool only loses the included transactions once the block is safely on disk
+        self.push_block(block)?;
+        self.revalidate_pool();
+        self.save_pool();
 
         println!(
-            "compute time: {:?}\nproof for the current block is: {}",
-            elapsed, proof_hash
+            "compute time: {:?} ({} H/s on {} threads)\nproof for the current block is: {}",
+            report.elapsed,
+            report.hashrate(),
+            miner.threads(),
+            proof_hash
         );
+        Ok(())
+    }
+
+    /// Unmined block on top of the tip, to be solved by a `Miner` outside the chain lock
+    pub fn block_template(&mut self) -> io::Result<Block> {
+        self.assemble_block(0, self.last_block().hash())
+    }
+
+    fn assemble_block(&mut self, nonce: u64, previous_hash: Vec<u8>) -> io::Result<Block> {
+        let mut block = Block::new(nonce, previous_hash);
+        block.difficulty = BlockChain::next_difficulty(&self.chain, self.network);
+        // a clock behind the network's would give a block the median time rule rejects
+        block.time_stamps = block.time_stamps.max(BlockChain::median_time_past(&self.chain) + 1);
+
+        self.expire_pool();
+        let (selected, fees) = self.select_transactions();
+        // the reward goes first, like a coinbase, and collects the fees of the block
+        let reward = Transaction::from_wallet_transaction(&WalletTransaction {
+            sender: BlockChain::MINING_SENDER.to_string(),
+            recipient: self.blockhain_address.clone(),
+            amount: self
+                .network
+                .params()
+                .reward
+                .checked_add(fees)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            fee: Amount::ZERO,
+            nonce: self.chain.len() as u64,
+            public_key: "".to_string(),
+            signature: "".to_string(),
+        });
+        block.transactions.push(reward.serialization());
+        let entries = self.transaction_pool.entries();
+        block.transactions.extend(selected.iter().map(|&i| entries[i].bytes.clone()));
+
+        Ok(block)
+    }
+
+    /*
+    pick pool transactions by fee per byte, highest first, until the block is full.
+    A transaction is only eligible once the previous nonce of its sender is
+    confirmed or already picked, so a block never holds a nonce gap.
+    Returns the pool positions in block order and the sum of their fees.
+     */
+    fn select_transactions(&self) -> (Vec<usize>, Amount) {
+        let mut candidates: Vec<(usize, Transaction, usize)> = self
+            .transaction_pool
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, entry.transaction.clone(), entry.bytes.len()))
+            .collect();
+        candidates.sort_by(|(_, a, a_size), (_, b, b_size)| {
+            // a.fee / a_size > b.fee / b_size, compared without division
+            let a_rate = a.fee.units() as u128 * *b_size as u128;
+            let b_rate = b.fee.units() as u128 * *a_size as u128;
+            b_rate.cmp(&a_rate)
+        });
+
+        let mut selected = Vec::<usize>::new();
+        let mut fees = Amount::ZERO;
+        let mut size = 0;
+        let mut nonces = HashMap::<Vec<u8>, u64>::new();
+        while let Some(position) = candidates.iter().position(|(_, tx, tx_size)| {
+            let next = nonces
+                .get(&tx.sender_address)
+                .copied()
+                .unwrap_or_else(|| self.index.next_nonce(&tx.sender_address));
+            tx.nonce == next && size + tx_size <= BlockChain::MAX_BLOCK_TRANSACTION_BYTES
+        }) {
+            let (i, tx, tx_size) = candidates.remove(position);
+            let Ok(total) = fees.checked_add(tx.fee) else {
+                break;
+            };
+            fees = total;
+            size += tx_size;
+            nonces.insert(tx.sender_address, tx.nonce + 1);
+            selected.push(i);
+        }
+
+        (selected, fees)
+    }
+
+    /// Replace the local chain with `chain` if it is valid and has more work, returns whether it was replaced
+    pub fn replace_chain(&mut self, chain: Vec<Block>) -> io::Result<bool> {
+        if BlockChain::chain_work(&chain) <= BlockChain::chain_work(&self.chain) {
+            return Ok(false);
+        }
+        // only the blocks after the fork point are new, and have to be rewritten
+        let fork = self
+            .chain
+            .iter()
+            .zip(chain.iter())
+            .take_while(|(ours, theirs)| ours == theirs)
+            .count();
+        if let Err(e) = BlockChain::is_valid_chain(&chain, self.network)
+            .and_then(|()| BlockChain::check_future_drift(&chain[fork..], fork))
+        {
+            log::warn!("rejecting received chain: {}", e);
+            return Ok(false);
+        }
+
+        if let Some(storage) = self.storage.as_mut() {
+            storage.truncate(fork)?;
+        }
+        self.chain.truncate(fork);
+        self.index.truncate(fork);
+        for block in chain.into_iter().skip(fork) {
+            self.push_block(block)?;
+        }
+
+        // a chain from another genesis has another chain id, and our pool was signed for ours
+        if fork == 0 {
+            self.drop_foreign_transactions();
+        }
+        // transactions confirmed or made invalid by the new chain leave the pool
+        self.revalidate_pool();
+        self.save_pool();
+
+        Ok(true)
+    }
+
+    /// Append a block received from a neighbour if it extends our tip, returns whether it was appended
+    pub fn add_block(&mut self, block: Block) -> io::Result<bool> {
+        if block.previous_hash != self.last_block().hash() {
+            return Ok(false);
+        }
+        if let Err(e) = BlockChain::validate_block(&self.chain, &self.index, &block, self.network)
+            .and_then(|()| BlockChain::check_future_drift(slice::from_ref(&block), self.chain.len()))
+        {
+            log::warn!("rejecting received block: {}", e);
+            return Ok(false);
+        }
+
+        self.push_block(block)?;
+        self.revalidate_pool();
+        self.save_pool();
+
+        Ok(true)
+    }
+
+    /// Find the block holding the transaction with hash `tx_hash` and prove its inclusion
+    pub fn transaction_proof(&self, tx_hash: &[u8]) -> Option<(usize, MerkleProof)> {
+        let height = self.index.height_of_transaction(tx_hash)?;
+        let proof = self.chain[height].merkle_proof(tx_hash)?;
+        Some((height, proof))
+    }
+
+    /// Look a block up by one of its fields
+    pub fn search_block(&self, search: BlockSearch) -> BlockSearchResult<'_> {
+        if self.chain.is_empty() {
+            return BlockSearchResult::FailOfEmptyBlocks;
+        }
+
+        match search {
+            BlockSearch::SearchByIndex(index) => match self.chain.get(index) {
+                Some(block) => BlockSearchResult::Success(block),
+                None => BlockSearchResult::FailOfIndex(index),
+            },
+            BlockSearch::SearchByPreviousHash(previous_hash) => {
+                // the block after the one whose hash is `previous_hash`, or the genesis block
+                let height = match self.index.height_of_hash(&previous_hash) {
+                    Some(height) => Some(height + 1),
+                    None => (self.chain[0].previous_hash == previous_hash).then_some(0),
+                };
+                match height.and_then(|height| self.chain.get(height)) {
+                    Some(block) => BlockSearchResult::Success(block),
+                    None => BlockSearchResult::FailOfPreviousHash(previous_hash),
+                }
+            }
+            BlockSearch::SearchByBlockHash(hash) => match self.index.height_of_hash(&hash) {
+                Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                None => BlockSearchResult::FailOfBlockHash(hash),
+            },
+            BlockSearch::SearchByTimeStamp(time_stamp) => {
+                match self.index.height_of_timestamp(time_stamp) {
+                    Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                    None => BlockSearchResult::FailOfTimeStamp(time_stamp),
+                }
+            }
+            BlockSearch::SearchByTransaction(tx_hash) => {
+                match self.index.height_of_transaction(&tx_hash) {
+                    Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                    None => BlockSearchResult::FailOfTransaction(tx_hash),
+                }
+            }
+            // nonces are not unique, the first block that used it is returned
+            BlockSearch::SearchByNonce(nonce) => match self.index.height_of_nonce(nonce) {
+                Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                None => BlockSearchResult::FailOfNonce(nonce),
+            },
+        }
+    }
+
+    /// Network whose consensus rules the chain follows
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Hash of the genesis block, signatures are only valid on the chain they name
+    pub fn chain_id(&self) -> Vec<u8> {
+        self.chain[0].hash()
     }
 
     /// Get the last block in the chain
@@ -198,107 +790,879 @@ impl BlockChain {
     }
 
       pub fn get_transactions(&self) -> Vec<Transaction> {
-        let mut transactions = Vec::<Transaction>::new();
-        for tx_in_pool in self.transaction_pool.iter() {
-            transactions.push(Transaction::deserialization(tx_in_pool.to_vec()));
-        }
+        self.transaction_pool
+            .entries()
+            .iter()
+            .map(|entry| entry.transaction.clone())
+            .collect()
+    }
 
+    /// Height of the block with hash `hash`
+    pub fn height_of_block(&self, hash: &[u8]) -> Option<usize> {
+        self.index.height_of_hash(hash)
+    }
+
+    /// Confirmed transactions from or to `address` in chain order, with their block height and hash
+    pub fn address_transactions(&self, address: &str) -> Vec<(usize, Vec<u8>, Transaction)> {
+        let address = address.as_bytes();
+        let mut transactions = Vec::<(usize, Vec<u8>, Transaction)>::new();
+        for &height in self.index.heights_involving(address) {
+            for bytes in &self.chain[height].transactions {
+                if let Ok(tx) = Transaction::deserialization(bytes)
+                    && (tx.sender_address == address || tx.recipient_address == address)
+                {
+                    transactions.push((height, Sha256::digest(bytes).to_vec(), tx));
+                }
+            }
+        }
         transactions
     }
 
+    /// Find a transaction by its hash, in the pool or in the chain
+    pub fn find_transaction(&self, tx_hash: &[u8]) -> Option<(Transaction, TransactionStatus)> {
+        if let Some(entry) = self
+            .transaction_pool
+            .entries()
+            .iter()
+            .find(|entry| entry.hash == tx_hash)
+        {
+            return Some((entry.transaction.clone(), TransactionStatus::Pending));
+        }
+
+        let block_height = self.index.height_of_transaction(tx_hash)?;
+        let block = &self.chain[block_height];
+        let tx = block
+            .transactions
+            .iter()
+            .find(|tx| Sha256::digest(tx).as_slice() == tx_hash)?;
+
+        let status = TransactionStatus::Confirmed {
+            block_height,
+            block_hash: block.hash(),
+            // the block holding the transaction counts as its first confirmation
+            confirmations: self.chain.len() - block_height,
+        };
+        Some((Transaction::deserialization(tx).ok()?, status))
+    }
+
     /// Add a transaction to the pool
-    pub fn add_transaction(&mut self, tx: &WalletTransaction) -> bool {
+    pub fn add_transaction(&mut self, tx: &WalletTransaction) -> Result<(), TransactionError> {
+        // expired transactions no longer hold their nonce or balance
+        self.expire_pool();
+
         // miners cannot send money to themselves
         if tx.sender == self.blockhain_address {
             println!("miner cannot send money to himself");
-            return false;
+            return Err(TransactionError::MinerSender);
         }
 
 
-        // Check if it's a miner reward transaction
-        let is_miner_reward = tx.sender == BlockChain::MINING_SENDER;
+        // rewards are only ever created by create_block
+        if tx.sender == BlockChain::MINING_SENDER {
+            return Err(TransactionError::RewardSender);
+        }
 
-        if !is_miner_reward {
-            // Normal transaction: verify signature
-            if !Wallet::verify_transaction(tx) {
-                println!("invalid transaction");
-                return false;
+        // a mistyped recipient fails its checksum, the funds would be lost otherwise
+        for address in [&tx.sender, &tx.recipient] {
+            match Address::parse_for(address, self.network) {
+                Ok(_) => {}
+                Err(AddressError::WrongNetwork { .. }) => return Err(TransactionError::WrongNetwork),
+                Err(_) if address == &tx.sender => return Err(TransactionError::SenderMismatch),
+                Err(_) => return Err(TransactionError::InvalidRecipient),
             }
-            // Normal transaction: check sender balance
-            // if self.calculate_total_amount(tx.sender.clone()) < tx.amount as i64 {
-            //     println!("Sender does not have enough balance");
-            //     return false;
-            // }
         }
 
-        // Serialize transaction
-        let transaction = Transaction::new(
-            tx.sender.as_bytes().to_vec(),
-            tx.recipient.as_bytes().to_vec(),
-            tx.amount,
-        );
+        // Normal transaction: the signing key must own the sender address
+        if !Wallet::is_sender_key(tx) {
+            return Err(TransactionError::SenderMismatch);
+        }
+        // Normal transaction: verify signature
+        if !Wallet::verify_transaction(tx, &self.chain_id()) {
+            println!("invalid transaction");
+            return Err(TransactionError::InvalidSignature);
+        }
+        if tx.amount.is_zero() {
+            return Err(TransactionError::InvalidAmount);
+        }
+        // Normal transaction: must be the sender's next transaction, this stops replays
+        let expected = self.next_nonce(&tx.sender);
+        if tx.nonce != expected {
+            return Err(TransactionError::InvalidNonce {
+                expected,
+                got: tx.nonce,
+            });
+        }
+        // Normal transaction: check sender balance, minus what it already spends in the pool
+        let available = self
+            .available_amount(&tx.sender)
+            .map_err(|_| TransactionError::AmountOverflow)?;
+        let required = tx
+            .amount
+            .checked_add(tx.fee)
+            .map_err(|_| TransactionError::AmountOverflow)?;
+        if available < required {
+            println!("Sender does not have enough balance");
+            return Err(TransactionError::InsufficientFunds {
+                available,
+                required,
+            });
+        }
+
+        // Serialize transaction, keeping the signature for later validation
+        let transaction = Transaction::from_wallet_transaction(tx);
+        // whatever enters the pool must decode again on every peer
+        if Transaction::deserialization(&transaction.serialization()).is_err() {
+            return Err(TransactionError::Malformed);
+        }
+
+        self.transaction_pool
+            .insert(MempoolEntry::new(transaction))
+            .map_err(|e| match e {
+                MempoolError::Duplicate => TransactionError::Duplicate,
+                MempoolError::SenderLimit => TransactionError::SenderLimit,
+                MempoolError::Full => TransactionError::PoolFull,
+            })?;
+        self.save_pool();
+        Ok(())
+    }
+
+    /// Nonce of the next transaction of `address`, counting its transactions in the pool
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        let pending = self
+            .transaction_pool
+            .entries()
+            .iter()
+            .filter(|entry| entry.transaction.sender_address == address.as_bytes())
+            .count() as u64;
+
+        self.index.next_nonce(address.as_bytes()) + pending
+    }
+
+    /// Confirmed balance of `address` minus its outgoing transactions still in the pool
+    pub fn available_amount(&self, address: &str) -> Result<Amount, AmountError> {
+        let pending = Amount::checked_sum(
+            self.get_transactions()
+                .iter()
+                .filter(|tx| tx.sender_address == address.as_bytes())
+                .map(|tx| tx.total_spent())
+                .collect::<Result<Vec<Amount>, AmountError>>()?,
+        )?;
+
+        // pending spends were checked against the balance, so this only saturates on a corrupt pool
+        let confirmed = self.calculate_total_amount(address.to_string())?;
+        Ok(confirmed.checked_sub(pending).unwrap_or(Amount::ZERO))
+    }
+
+    /// Drop the pool transactions older than the mempool TTL
+    pub fn expire_pool(&mut self) {
+        let expired = self.transaction_pool.expire();
+        if expired > 0 {
+            log::info!("{} transaction(s) expired from the pool", expired);
+            self.save_pool();
+        }
+    }
+
+    /*
+    drop the pool entries the chain no longer accepts: confirmed transactions
+    and ones whose nonce is stale all fail the nonce check, and a sender whose
+    balance dropped loses the transactions it can no longer pay for, with the
+    ones after them.
+     */
+    fn revalidate_pool(&mut self) {
+        let mut pending: Vec<&MempoolEntry> = self.transaction_pool.entries().iter().collect();
+        pending.sort_by(|a, b| {
+            (&a.transaction.sender_address, a.transaction.nonce)
+                .cmp(&(&b.transaction.sender_address, b.transaction.nonce))
+        });
+
+        let mut keep = HashSet::<Vec<u8>>::new();
+        let mut senders = HashMap::<&[u8], (u64, Amount)>::new();
+        for entry in pending {
+            let sender = entry.transaction.sender_address.as_slice();
+            let (next_nonce, balance) = senders.entry(sender).or_insert_with(|| {
+                let balance = String::from_utf8(sender.to_vec())
+                    .ok()
+                    .and_then(|address| self.calculate_total_amount(address).ok())
+                    .unwrap_or(Amount::ZERO);
+                (self.index.next_nonce(sender), balance)
+            });
+            let Ok(spent) = entry.transaction.total_spent() else {
+                continue;
+            };
+            if entry.transaction.nonce != *next_nonce || *balance < spent {
+                continue;
+            }
+            *next_nonce += 1;
+            *balance = balance.checked_sub(spent).unwrap_or(Amount::ZERO);
+            keep.insert(entry.hash.clone());
+        }
+
+        self.transaction_pool.retain(|entry| keep.contains(&entry.hash));
+    }
+
+    /// Drop pool transactions whose signature is not for this chain
+    fn drop_foreign_transactions(&mut self) {
+        let chain_id = self.chain_id();
+        self.transaction_pool.retain(|entry| {
+            Wallet::verify_transaction(&entry.transaction.to_wallet_transaction(), &chain_id)
+        });
+    }
+
+    /// Difficulty the block following `chain` has to meet
+    ///
+    /// Like Bitcoin, the difficulty only changes every `RETARGET_INTERVAL` blocks, by
+    /// comparing how long the last interval took against the target block time of `network`.
+    pub fn next_difficulty(chain: &[Block], network: Network) -> u32 {
+        let params = network.params();
+        let height = chain.len();
+        // block 1 is the first mined block, the genesis block has no difficulty
+        let Some(last) = chain.last().filter(|_| height > 1) else {
+            return params.initial_difficulty;
+        };
+        if !height.is_multiple_of(BlockChain::RETARGET_INTERVAL) || height <= BlockChain::RETARGET_INTERVAL {
+            return last.difficulty;
+        }
+
+        let first = &chain[height - 1 - BlockChain::RETARGET_INTERVAL];
+        let expected = params.target_block_time.as_nanos() * BlockChain::RETARGET_INTERVAL as u128;
+        let actual = last.time_stamps.saturating_sub(first.time_stamps).max(1);
 
-        // Prevent duplicates
-        if self.transaction_pool.iter().any(|t| *t == transaction.serialization()) {
-            return false;
+        // each bit doubles the work, move at most 2 bits (a factor of 4) per retarget: the
+        // ratio is rounded to a power of two against 2^0.5 and 2^1.5 in thousandths, integer
+        // math so that every node takes the same side of a boundary
+        let thresholds = [1414u128, 2828];
+        let steps = |a: u128, b: u128| {
+            thresholds
+                .iter()
+                .filter(|&&threshold| a.saturating_mul(1000) >= b.saturating_mul(threshold))
+                .count() as i64
+        };
+        let adjustment = steps(expected, actual) - steps(actual, expected);
+        (last.difficulty as i64 + adjustment).clamp(
+            params.min_difficulty as i64,
+            BlockChain::MAX_DIFFICULTY as i64,
+        ) as u32
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`
+    ///
+    /// Unlike the timestamp of the tip, a single miner cannot move it, so the
+    /// time the retarget sees can only go forward.
+    pub fn median_time_past(chain: &[Block]) -> u128 {
+        let start = chain.len().saturating_sub(BlockChain::MEDIAN_TIME_SPAN);
+        let mut times: Vec<u128> = chain[start..].iter().map(|block| block.time_stamps).collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Total work of a chain, the fork choice prefers the chain with the most work
+    pub fn chain_work(chain: &[Block]) -> u128 {
+        chain
+            .iter()
+            .fold(0u128, |total, block| total.saturating_add(block.work()))
+    }
+
+    /// Check that `chain` links up, meets the difficulty and only holds valid transactions
+    pub fn is_valid_chain(chain: &[Block], network: Network) -> Result<(), ChainError> {
+        if chain.is_empty() {
+            return Err(ChainError::EmptyChain);
+        }
+        // a chain of another network starts from another genesis block
+        if !network.is_genesis(&chain[0]) {
+            return Err(ChainError::Genesis);
         }
 
-        self.transaction_pool.push(transaction.serialization());
-        true
+        // the genesis block is neither mined nor rewarded, only the blocks after it are checked
+        let mut chain_index = ChainIndex::build(&chain[..1]);
+        for index in 1..chain.len() {
+            BlockChain::validate_block(&chain[..index], &chain_index, &chain[index], network)?;
+            chain_index.insert(index, &chain[index]);
+        }
+
+        Ok(())
+    }
+
+    /// Check that none of `blocks`, the first of which has height `start`, is ahead of our clock
+    ///
+    /// Unlike the other rules this depends on when a block arrives, so it only applies to
+    /// blocks received from peers. The stored chain is not checked again on open, or a clock
+    /// set back would throw away blocks that were accepted before.
+    fn check_future_drift(blocks: &[Block], start: usize) -> Result<(), ChainError> {
+        let latest = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            + BlockChain::MAX_FUTURE_DRIFT;
+        match blocks.iter().position(|block| block.time_stamps > latest.as_nanos()) {
+            Some(offset) => Err(ChainError::TimeStampInFuture { index: start + offset }),
+            None => Ok(()),
+        }
     }
 
-    /// Proof-of-Work algorithm
-    fn do_proof_of_work(block: &mut Block) -> String {
-        let target = "0".repeat(BlockChain::DIFFICULTY);
-        loop {
-            let hash = block.hash();
-            let hash_str = hex::encode(&hash);
-            if &hash_str[0..BlockChain::DIFFICULTY] == target {
-                return hash_str;
+    /// Check that `block` is a valid successor of `chain`, whose index is `chain_index`
+    fn validate_block(
+        chain: &[Block],
+        chain_index: &ChainIndex,
+        block: &Block,
+        network: Network,
+    ) -> Result<(), ChainError> {
+        let index = chain.len();
+        let Some(previous) = chain.last() else {
+            return Err(ChainError::EmptyChain);
+        };
+
+        if block.previous_hash != previous.hash() {
+            return Err(ChainError::PreviousHash { index });
+        }
+
+        // the retarget trusts timestamps, so they only go forward; see also check_future_drift
+        if block.time_stamps <= BlockChain::median_time_past(chain) {
+            return Err(ChainError::TimeStampTooOld { index });
+        }
+
+        if block.difficulty != BlockChain::next_difficulty(chain, network) {
+            return Err(ChainError::Difficulty { index });
+        }
+
+        if !block.meets_difficulty() {
+            return Err(ChainError::ProofOfWork { index });
+        }
+
+        let mut rewards = Vec::<Amount>::new();
+        let mut fees = Amount::ZERO;
+        // next nonce of every sender seen so far in this block
+        let mut nonces = HashMap::<Vec<u8>, u64>::new();
+        // what every sender seen so far in this block has left to spend
+        let mut balances = HashMap::<Vec<u8>, Amount>::new();
+        for (transaction, tx) in block.transactions.iter().enumerate() {
+            let Ok(tx) = Transaction::deserialization(tx) else {
+                return Err(ChainError::MalformedTransaction { index, transaction });
+            };
+
+            let wallet_tx = tx.to_wallet_transaction();
+            // rewards and payments alike only go to addresses of this network
+            if Address::parse_for(&wallet_tx.recipient, network).is_err() {
+                return Err(ChainError::WrongNetwork { index, transaction });
             }
-            *block += 1;
+            if tx.sender_address == BlockChain::MINING_SENDER.as_bytes() {
+                // the reward carries the block height so every reward has its own id
+                if tx.nonce != index as u64 {
+                    return Err(ChainError::Nonce { index, transaction });
+                }
+                rewards.push(tx.value);
+                continue;
+            }
+
+            fees = fees
+                .checked_add(tx.fee)
+                .map_err(|_| ChainError::InvalidReward { index })?;
+
+            let expected = nonces
+                .entry(tx.sender_address.clone())
+                .or_insert_with(|| chain_index.next_nonce(&tx.sender_address));
+            if tx.nonce != *expected {
+                return Err(ChainError::Nonce { index, transaction });
+            }
+            *expected += 1;
+
+            if Address::parse_for(&wallet_tx.sender, network).is_err() {
+                return Err(ChainError::WrongNetwork { index, transaction });
+            } else if !Wallet::is_sender_key(&wallet_tx) {
+                return Err(ChainError::SenderMismatch { index, transaction });
+            } else if !Wallet::verify_transaction(&wallet_tx, &chain[0].hash()) {
+                return Err(ChainError::InvalidSignature { index, transaction });
+            }
+
+            // like the pool, only confirmed coins can be spent, not ones received in this block
+            let available = match balances.get(&tx.sender_address) {
+                Some(balance) => *balance,
+                // the chain before this block is valid, so its balances are never negative
+                None => BlockChain::confirmed_balance(chain, chain_index, &tx.sender_address)
+                    .unwrap_or(Amount::ZERO),
+            };
+            let remaining = tx
+                .total_spent()
+                .and_then(|spent| available.checked_sub(spent))
+                .map_err(|_| ChainError::InsufficientFunds { index, transaction })?;
+            balances.insert(tx.sender_address.clone(), remaining);
         }
+
+        let reward = match rewards[..] {
+            [] => return Err(ChainError::MissingReward { index }),
+            [reward] => reward,
+            _ => return Err(ChainError::DuplicateReward { index }),
+        };
+        // the miner earns the block reward plus the fees of the block, nothing more
+        if network.params().reward.checked_add(fees) != Ok(reward) {
+            return Err(ChainError::InvalidReward { index });
+        }
+
+        Ok(())
     }
 
     /// Mine a new block with reward transaction
     pub fn mining(&mut self) -> bool {
+        match self.mine_block() {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("failed to store mined block: {}", e);
+                false
+            }
+        }
+    }
+
+    fn mine_block(&mut self) -> io::Result<()> {
         /*
         When a block is minted, a transaction is created to reward the miner.
-        Miner reward does not require signature verification or balance check.
+        Miner reward does not require signature verification or balance check,
+        it is built by create_block and never goes through the pool.
         */
-        let tx = WalletTransaction {
-            sender: BlockChain::MINING_SENDER.to_string(),
-            recipient: self.blockhain_address.clone(),
-            amount: BlockChain::MINING_REWARD,
-            public_key: "".to_string(),
-            signature: "".to_string(),
-        };
-
-        self.add_transaction(&tx); // always succeeds
-        self.create_block(0, self.last_block().hash());
-        true
+        self.create_block(0, self.last_block().hash())
     }
 
     /// Calculate the total balance for an address
-    pub fn calculate_total_amount(&self, address: String) -> f64 {
-        let mut total_amount: i64 = 0;
-        let address_bytes = address.as_bytes();
+    pub fn calculate_total_amount(&self, address: String) -> Result<Amount, AmountError> {
+        BlockChain::confirmed_balance(&self.chain, &self.index, address.as_bytes())
+    }
+
+    /// Balance of `address` in `chain`, whose index is `chain_index`
+    fn confirmed_balance(
+        chain: &[Block],
+        chain_index: &ChainIndex,
+        address_bytes: &[u8],
+    ) -> Result<Amount, AmountError> {
+        let mut received = Amount::ZERO;
+        let mut sent = Amount::ZERO;
 
-        // iterate over all blocks
-        for block in &self.chain {
-            for t in &block.transactions {
-                let tx = Transaction::deserialization(t.clone());
+        // only the blocks the address appears in
+        for &height in chain_index.heights_involving(address_bytes) {
+            for t in &chain[height].transactions {
+                // validated chains only hold decodable transactions
+                let Ok(tx) = Transaction::deserialization(t) else {
+                    continue;
+                };
 
                 if tx.recipient_address == address_bytes {
-                    total_amount += tx.value as i64;
+                    received = received.checked_add(tx.value)?;
                 }
 
                 if tx.sender_address == address_bytes {
-                    total_amount -= tx.value as i64;
+                    sent = sent.checked_add(tx.total_spent()?)?;
                 }
             }
         }
 
-        total_amount as f64
+        received.checked_sub(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK: Network = Network::Devnet;
+
+    // the genesis block and one mined block paying the reward to `miner`
+    fn funded_chain(miner: &Wallet) -> BlockChain {
+        BlockChain::new(miner.get_adress(), NETWORK)
+    }
+
+    fn reward(height: usize, recipient: &str, amount: Amount) -> Vec<u8> {
+        Transaction::from_wallet_transaction(&WalletTransaction {
+            sender: BlockChain::MINING_SENDER.to_string(),
+            recipient: recipient.to_string(),
+            amount,
+            fee: Amount::ZERO,
+            nonce: height as u64,
+            public_key: String::new(),
+            signature: String::new(),
+        })
+        .serialization()
+    }
+
+    // unmined successor of the tip holding `payments` after a reward that collects their fees
+    fn template(bc: &BlockChain, payments: &[WalletTransaction]) -> Block {
+        let mut block = Block::new(0, bc.last_block().hash());
+        block.difficulty = BlockChain::next_difficulty(&bc.chain, NETWORK);
+        let fees = Amount::checked_sum(payments.iter().map(|tx| tx.fee)).unwrap();
+        let amount = NETWORK.params().reward.checked_add(fees).unwrap();
+        block.transactions.push(reward(bc.chain.len(), &bc.blockhain_address, amount));
+        for tx in payments {
+            block.transactions.push(Transaction::from_wallet_transaction(tx).serialization());
+        }
+        block
+    }
+
+    fn solve(mut block: Block) -> Block {
+        block.nonce = 0;
+        while !block.meets_difficulty() {
+            block += 1;
+        }
+        block
     }
-}
\ No newline at end of file
+
+    // `coins` from `from` to a new address, signed for `bc`
+    fn pay(from: &mut Wallet, coins: u64, fee: Amount, nonce: u64, bc: &BlockChain) -> WalletTransaction {
+        let recipient = Wallet::new(NETWORK).get_adress();
+        let amount = Amount::from_units(coins * Amount::COIN.units());
+        from.sign_transaction(&recipient, amount, fee, nonce, &bc.chain_id())
+    }
+
+    // validate the chain of `bc` followed by `block`
+    fn check(bc: &BlockChain, block: Block) -> Result<(), ChainError> {
+        let mut chain = bc.chain.clone();
+        chain.push(block);
+        BlockChain::is_valid_chain(&chain, NETWORK)
+    }
+
+    #[test]
+    fn valid_block_is_accepted() {
+        let mut miner = Wallet::new(NETWORK);
+        let mut bc = funded_chain(&miner);
+        assert_eq!(BlockChain::is_valid_chain(&bc.chain, NETWORK), Ok(()));
+
+        // two payments of one sender in nonce order, together within its balance
+        let payments = [
+            pay(&mut miner, 20, Amount::from_units(10), 0, &bc),
+            pay(&mut miner, 20, Amount::from_units(10), 1, &bc),
+        ];
+        let block = solve(template(&bc, &payments));
+        assert_eq!(check(&bc, block.clone()), Ok(()));
+
+        assert!(bc.add_block(block).unwrap());
+        assert_eq!(bc.chain.len(), 3);
+        assert_eq!(bc.index.next_nonce(miner.get_adress().as_bytes()), 2);
+    }
+
+    #[test]
+    fn empty_chain_and_foreign_genesis_are_refused() {
+        assert_eq!(BlockChain::is_valid_chain(&[], NETWORK), Err(ChainError::EmptyChain));
+        assert_eq!(
+            BlockChain::is_valid_chain(&[Network::Testnet.genesis()], NETWORK),
+            Err(ChainError::Genesis)
+        );
+    }
+
+    #[test]
+    fn broken_previous_hash_is_refused() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut block = template(&bc, &[]);
+        block.previous_hash = bc.chain[0].hash();
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::PreviousHash { index: 2 }));
+    }
+
+    #[test]
+    fn failed_proof_of_work_is_refused() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut block = solve(template(&bc, &[]));
+        while block.meets_difficulty() {
+            block += 1;
+        }
+        assert_eq!(check(&bc, block), Err(ChainError::ProofOfWork { index: 2 }));
+    }
+
+    #[test]
+    fn wrong_difficulty_is_refused() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut block = template(&bc, &[]);
+        // more work than asked for is still not the difficulty of the retarget rule
+        block.difficulty += 1;
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::Difficulty { index: 2 }));
+    }
+
+    #[test]
+    fn timestamp_not_after_the_median_is_refused() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut block = template(&bc, &[]);
+        block.time_stamps = BlockChain::median_time_past(&bc.chain);
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::TimeStampTooOld { index: 2 }));
+    }
+
+    #[test]
+    fn bad_signature_is_refused() {
+        let mut miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+
+        let mut payment = pay(&mut miner, 1, Amount::ZERO, 0, &bc);
+        payment.amount = Amount::from_units(2 * Amount::COIN.units());
+        let block = solve(template(&bc, &[payment]));
+        assert_eq!(
+            check(&bc, block),
+            Err(ChainError::InvalidSignature { index: 2, transaction: 1 })
+        );
+
+        // signed for another chain
+        let recipient = Wallet::new(NETWORK).get_adress();
+        let payment = miner.sign_transaction(&recipient, Amount::COIN, Amount::ZERO, 0, &[0; 32]);
+        let block = solve(template(&bc, &[payment]));
+        assert_eq!(
+            check(&bc, block),
+            Err(ChainError::InvalidSignature { index: 2, transaction: 1 })
+        );
+    }
+
+    #[test]
+    fn key_that_does_not_own_the_sender_is_refused() {
+        let miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+
+        let mut payment = pay(&mut Wallet::new(NETWORK), 1, Amount::ZERO, 0, &bc);
+        payment.sender = miner.get_adress();
+        let block = solve(template(&bc, &[payment]));
+        assert_eq!(
+            check(&bc, block),
+            Err(ChainError::SenderMismatch { index: 2, transaction: 1 })
+        );
+    }
+
+    #[test]
+    fn address_of_another_network_is_refused() {
+        let mut miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+
+        let recipient = Wallet::new(Network::Testnet).get_adress();
+        let payment = miner.sign_transaction(&recipient, Amount::COIN, Amount::ZERO, 0, &bc.chain_id());
+        let block = solve(template(&bc, &[payment]));
+        assert_eq!(
+            check(&bc, block),
+            Err(ChainError::WrongNetwork { index: 2, transaction: 1 })
+        );
+    }
+
+    #[test]
+    fn missing_or_duplicate_reward_is_refused() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+
+        let mut block = template(&bc, &[]);
+        block.transactions.clear();
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::MissingReward { index: 2 }));
+
+        let mut block = template(&bc, &[]);
+        block.transactions.push(block.transactions[0].clone());
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::DuplicateReward { index: 2 }));
+    }
+
+    #[test]
+    fn reward_must_carry_the_block_height() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut block = template(&bc, &[]);
+        block.transactions[0] = reward(1, &bc.blockhain_address, NETWORK.params().reward);
+        assert_eq!(
+            check(&bc, solve(block)),
+            Err(ChainError::Nonce { index: 2, transaction: 0 })
+        );
+    }
+
+    #[test]
+    fn out_of_order_nonces_are_refused() {
+        let mut miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+
+        // a gap before the first transaction of the sender
+        let payments = [pay(&mut miner, 1, Amount::ZERO, 1, &bc)];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::Nonce { index: 2, transaction: 1 })
+        );
+
+        // a replay of the same nonce within the block
+        let payments = [
+            pay(&mut miner, 1, Amount::ZERO, 0, &bc),
+            pay(&mut miner, 2, Amount::ZERO, 0, &bc),
+        ];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::Nonce { index: 2, transaction: 2 })
+        );
+
+        // nonces in reverse order
+        let payments = [
+            pay(&mut miner, 1, Amount::ZERO, 1, &bc),
+            pay(&mut miner, 1, Amount::ZERO, 0, &bc),
+        ];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::Nonce { index: 2, transaction: 1 })
+        );
+    }
+
+    #[test]
+    fn confirmed_nonce_cannot_be_replayed() {
+        let mut miner = Wallet::new(NETWORK);
+        let mut bc = funded_chain(&miner);
+        let payment = pay(&mut miner, 1, Amount::ZERO, 0, &bc);
+        assert!(bc.add_block(solve(template(&bc, std::slice::from_ref(&payment)))).unwrap());
+
+        assert_eq!(
+            check(&bc, solve(template(&bc, &[payment]))),
+            Err(ChainError::Nonce { index: 3, transaction: 1 })
+        );
+    }
+
+    #[test]
+    fn reward_must_be_the_block_reward_plus_fees() {
+        let mut miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+        let fee = Amount::from_units(12_345);
+        let payments = [pay(&mut miner, 1, fee, 0, &bc)];
+
+        // the template collects the fee
+        assert_eq!(check(&bc, solve(template(&bc, &payments))), Ok(()));
+
+        // leaving the fee out is as wrong as taking more
+        let reward_only = NETWORK.params().reward;
+        let too_much = reward_only.checked_add(fee).unwrap().checked_add(fee).unwrap();
+        for amount in [reward_only, too_much] {
+            let mut block = template(&bc, &payments);
+            block.transactions[0] = reward(2, &bc.blockhain_address, amount);
+            assert_eq!(check(&bc, solve(block)), Err(ChainError::InvalidReward { index: 2 }));
+        }
+
+        // without payments the reward is exactly the block reward
+        let mut block = template(&bc, &[]);
+        let amount = reward_only.checked_add(fee).unwrap();
+        block.transactions[0] = reward(2, &bc.blockhain_address, amount);
+        assert_eq!(check(&bc, solve(block)), Err(ChainError::InvalidReward { index: 2 }));
+    }
+
+    #[test]
+    fn spending_more_than_the_balance_is_refused() {
+        let mut miner = Wallet::new(NETWORK);
+        let bc = funded_chain(&miner);
+
+        // the reward of block 1 is 50 coins, the fee has to be paid too
+        let payments = [pay(&mut miner, 50, Amount::from_units(1), 0, &bc)];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::InsufficientFunds { index: 2, transaction: 1 })
+        );
+
+        // each payment is affordable, both together are not
+        let payments = [
+            pay(&mut miner, 30, Amount::ZERO, 0, &bc),
+            pay(&mut miner, 30, Amount::ZERO, 1, &bc),
+        ];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::InsufficientFunds { index: 2, transaction: 2 })
+        );
+
+        // a sender without confirmed coins cannot spend what it receives in the same block
+        let mut poor = Wallet::new(NETWORK);
+        let chain_id = bc.chain_id();
+        let funding = miner.sign_transaction(&poor.get_adress(), Amount::COIN, Amount::ZERO, 0, &chain_id);
+        let payments = [funding, pay(&mut poor, 1, Amount::ZERO, 0, &bc)];
+        assert_eq!(
+            check(&bc, solve(template(&bc, &payments))),
+            Err(ChainError::InsufficientFunds { index: 2, transaction: 2 })
+        );
+    }
+
+    // `len` blocks of `difficulty`, `spacing` nanoseconds apart
+    fn spaced(len: usize, spacing: u128, difficulty: u32) -> Vec<Block> {
+        (0..len)
+            .map(|height| {
+                let mut block = NETWORK.genesis();
+                block.time_stamps = height as u128 * spacing;
+                block.difficulty = difficulty;
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn difficulty_only_changes_at_retarget_heights() {
+        let initial = NETWORK.params().initial_difficulty;
+        assert_eq!(BlockChain::next_difficulty(&spaced(1, 1, 9), NETWORK), initial);
+        // the first interval keeps the initial difficulty whatever its timestamps
+        assert_eq!(BlockChain::next_difficulty(&spaced(10, 1, 9), NETWORK), 9);
+        assert_eq!(BlockChain::next_difficulty(&spaced(15, 1, 9), NETWORK), 9);
+        assert_eq!(BlockChain::next_difficulty(&spaced(21, 1, 9), NETWORK), 9);
+    }
+
+    #[test]
+    fn retarget_follows_the_block_time() {
+        let target = NETWORK.params().target_block_time.as_nanos();
+        let difficulty = |spacing: u128, difficulty: u32| {
+            BlockChain::next_difficulty(&spaced(20, spacing, difficulty), NETWORK)
+        };
+
+        assert_eq!(difficulty(target, 9), 9);
+        // twice and four times too fast add one and two bits, never more than two
+        assert_eq!(difficulty(target / 2, 9), 10);
+        assert_eq!(difficulty(target / 4, 9), 11);
+        assert_eq!(difficulty(0, 9), 11);
+        // and the same for too slow
+        assert_eq!(difficulty(target * 2, 9), 8);
+        assert_eq!(difficulty(target * 4, 9), 7);
+        assert_eq!(difficulty(target * 1000, 9), 7);
+        // within the bounds of the network
+        let min = NETWORK.params().min_difficulty;
+        assert_eq!(difficulty(target * 4, min), min);
+        assert_eq!(difficulty(0, BlockChain::MAX_DIFFICULTY), BlockChain::MAX_DIFFICULTY);
+    }
+
+    #[test]
+    fn retarget_rounds_at_fixed_thresholds() {
+        // the interval spans 10 block times, a ratio of exactly 1.414 is the first to round up
+        let expected = NETWORK.params().target_block_time.as_nanos() * 10;
+        let at = expected * 1000 / 1414;
+        let interval = |actual: u128| {
+            let mut chain = spaced(20, 0, 9);
+            chain[19].time_stamps = actual;
+            BlockChain::next_difficulty(&chain, NETWORK)
+        };
+        assert_eq!(interval(at), 10);
+        assert_eq!(interval(at + 1), 9);
+
+        let at = expected * 1414 / 1000;
+        assert_eq!(interval(at), 8);
+        assert_eq!(interval(at - 1), 9);
+    }
+
+    #[test]
+    fn retargeted_chain_is_valid() {
+        let bc = funded_chain(&Wallet::new(NETWORK));
+        let mut chain = bc.chain.clone();
+        while chain.len() <= 2 * BlockChain::RETARGET_INTERVAL {
+            let mut block = Block::new(0, chain.last().unwrap().hash());
+            // blocks far faster than the target raise the difficulty at every retarget
+            block.time_stamps = chain.last().unwrap().time_stamps + 1;
+            block.difficulty = BlockChain::next_difficulty(&chain, NETWORK);
+            let amount = NETWORK.params().reward;
+            block.transactions.push(reward(chain.len(), &bc.blockhain_address, amount));
+            chain.push(solve(block));
+        }
+        assert_eq!(BlockChain::is_valid_chain(&chain, NETWORK), Ok(()));
+        assert_eq!(chain[20].difficulty, NETWORK.params().initial_difficulty + 2);
+
+        let mut block = chain.pop().unwrap();
+        block.difficulty -= 2;
+        chain.push(solve(block));
+        assert_eq!(
+            BlockChain::is_valid_chain(&chain, NETWORK),
+            Err(ChainError::Difficulty { index: 20 })
+        );
+    }
+
+    #[test]
+    fn future_blocks_are_refused_from_peers_but_kept_on_reopen() {
+        let dir = std::env::temp_dir().join(format!("chain-future-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let miner = Wallet::new(NETWORK);
+        let mut bc = BlockChain::open(miner.get_adress(), &dir, NETWORK).unwrap();
+
+        let ahead = (BlockChain::MAX_FUTURE_DRIFT * 10).as_nanos();
+        let mut block = template(&bc, &[]);
+        block.time_stamps += ahead;
+        let block = solve(block);
+        assert!(!bc.add_block(block.clone()).unwrap());
+        assert_eq!(bc.chain.len(), 2);
+
+        // a block accepted while our clock was ahead stays when the clock goes back
+        bc.push_block(block).unwrap();
+        let bc = BlockChain::open(miner.get_adress(), &dir, NETWORK).unwrap();
+        assert_eq!(bc.chain.len(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
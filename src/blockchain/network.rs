@@ -0,0 +1,139 @@
+use crate::blockchain::{Block, amount::Amount};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+
+/*
+Networks running side by side, each with its own chain and addresses.
+
+The network picks the address version byte, so an address names the network
+it belongs to and a wallet of one network cannot pay into another, and the
+genesis block, so chains of different networks never share a first block.
+It also picks the block reward and how hard mining is: a devnet mines in a
+blink so it can be reset at will, a testnet mimics mainnet at a lower cost.
+
+Mainnet keeps the genesis marker and the address version it had before
+networks existed, so chains and wallets made back then stay valid.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Devnet,
+}
+
+/// Consensus values that differ between networks
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NetworkParams {
+    pub address_version: u8,
+    // the genesis block points back to this marker instead of a block hash
+    #[serde(with = "hex::serde")]
+    pub genesis_marker: &'static [u8],
+    pub reward: Amount,
+    pub initial_difficulty: u32,
+    pub min_difficulty: u32,
+    #[serde(skip)]
+    pub target_block_time: Duration,
+}
+
+/// Name that is not one of the networks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownNetwork(pub String);
+
+impl fmt::Display for UnknownNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown network {:?}, expected mainnet, testnet or devnet", self.0)
+    }
+}
+
+impl std::error::Error for UnknownNetwork {}
+
+impl Network {
+    pub const ALL: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Devnet];
+
+    const MAINNET: NetworkParams = NetworkParams {
+        address_version: 0x00,
+        genesis_marker: &[0, 32],
+        reward: Amount::COIN,
+        initial_difficulty: 12,
+        min_difficulty: 4,
+        target_block_time: Duration::from_secs(30),
+    };
+
+    // version 0x6f gives addresses starting with m or n
+    const TESTNET: NetworkParams = NetworkParams {
+        address_version: 0x6f,
+        genesis_marker: b"testnet",
+        reward: Amount::COIN,
+        initial_difficulty: 8,
+        min_difficulty: 4,
+        target_block_time: Duration::from_secs(30),
+    };
+
+    // version 0x1e gives addresses starting with D
+    const DEVNET: NetworkParams = NetworkParams {
+        address_version: 0x1e,
+        genesis_marker: b"devnet",
+        reward: Amount::from_units(50 * Amount::COIN.units()),
+        initial_difficulty: 4,
+        min_difficulty: 1,
+        target_block_time: Duration::from_secs(5),
+    };
+
+    pub fn params(self) -> &'static NetworkParams {
+        match self {
+            Network::Mainnet => &Network::MAINNET,
+            Network::Testnet => &Network::TESTNET,
+            Network::Devnet => &Network::DEVNET,
+        }
+    }
+
+    /// Network whose addresses carry `version`
+    pub fn from_address_version(version: u8) -> Option<Network> {
+        Network::ALL
+            .into_iter()
+            .find(|network| network.params().address_version == version)
+    }
+
+    /// First block of the chain, neither mined nor rewarded
+    pub fn genesis(self) -> Block {
+        Block {
+            nonce: 0,
+            previous_hash: self.params().genesis_marker.to_vec(),
+            time_stamps: 0,
+            difficulty: 0,
+            transactions: vec![],
+        }
+    }
+
+    /// Whether `block` can start a chain of this network
+    ///
+    /// Only the marker is compared, genesis blocks made before networks existed
+    /// carry the time they were created at.
+    pub fn is_genesis(self, block: &Block) -> bool {
+        block.previous_hash == self.params().genesis_marker && block.transactions.is_empty()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Devnet => "devnet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = UnknownNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Network::ALL
+            .into_iter()
+            .find(|network| network.to_string() == s)
+            .ok_or_else(|| UnknownNetwork(s.to_string()))
+    }
+}
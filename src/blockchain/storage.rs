@@ -0,0 +1,361 @@
+use crate::blockchain::{Block, Serialization};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/*
+Both files on disk are a sequence of records:
+
+    [payload length: u32 big endian][sha256 of payload: 32 bytes][payload]
+
+blocks.dat is append only, one record per block in chain order. A failed append
+is cut off again right away, and the next one is written over it anyway. A crash
+in the middle of an append leaves a short or corrupt record at the tail; it is
+cut off the next time the file is opened. A record that is whole but holds a
+block this node cannot decode is an error instead, the file is left as it is.
+
+mempool.dat holds one record per pending transaction and is rewritten as a whole
+through a temporary file and a rename, so it is either the old or the new pool.
+
+Block payloads use the binary codec. Older nodes wrote them as JSON, which
+always starts with '{' and never collides with a version byte; such files are
+read as they are and rewritten in the binary format once on open.
+*/
+const BLOCKS_FILE: &str = "blocks.dat";
+const MEMPOOL_FILE: &str = "mempool.dat";
+const MEMPOOL_TMP_FILE: &str = "mempool.dat.tmp";
+const BLOCKS_TMP_FILE: &str = "blocks.dat.tmp";
+const RECORD_HEADER_LEN: usize = 4 + 32;
+
+/// On-disk storage for the chain and the transaction pool of one node
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    // offset of every block record in blocks.dat, indexed by block height
+    offsets: Vec<u64>,
+    // length of the valid part of blocks.dat
+    end: u64,
+}
+
+impl Storage {
+    /// Open the storage directory and load the chain and pool kept in it
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<(Storage, Vec<Block>, Vec<Vec<u8>>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut storage = Storage {
+            dir,
+            offsets: Vec::new(),
+            end: 0,
+        };
+        let chain = storage.load_blocks()?;
+        let transaction_pool = storage.load_pool()?;
+
+        Ok((storage, chain, transaction_pool))
+    }
+
+    /// Append a block to the end of blocks.dat and flush it to disk
+    pub fn append_block(&mut self, block: &Block) -> io::Result<()> {
+        let payload = block.serialization();
+        let record = encode_record(&payload)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.dir.join(BLOCKS_FILE))?;
+        // the record goes right after the last good one, over whatever a failed append left
+        let end = self.end + record.len() as u64;
+        let written = file
+            .seek(SeekFrom::Start(self.end))
+            .and_then(|_| file.write_all(&record))
+            .and_then(|_| file.set_len(end))
+            .and_then(|_| file.sync_data());
+        if let Err(e) = written {
+            // a partial record would be read as the end of the chain, with every block after it
+            if let Err(cut) = file.set_len(self.end) {
+                log::error!("failed to cut off a partial block record: {}", cut);
+            }
+            return Err(e);
+        }
+
+        self.offsets.push(self.end);
+        self.end = end;
+        Ok(())
+    }
+
+    /// Drop every block from `height` onwards
+    pub fn truncate(&mut self, height: usize) -> io::Result<()> {
+        if height >= self.offsets.len() {
+            return Ok(());
+        }
+
+        let end = self.offsets[height];
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.dir.join(BLOCKS_FILE))?;
+        file.set_len(end)?;
+        file.sync_data()?;
+
+        self.offsets.truncate(height);
+        self.end = end;
+        Ok(())
+    }
+
+    /// Replace the stored transaction pool
+    pub fn save_pool(&self, transaction_pool: &[Vec<u8>]) -> io::Result<()> {
+        let mut bin = Vec::<u8>::new();
+        for tx in transaction_pool {
+            bin.extend(encode_record(tx)?);
+        }
+
+        let tmp_path = self.dir.join(MEMPOOL_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bin)?;
+        file.sync_data()?;
+        fs::rename(tmp_path, self.dir.join(MEMPOOL_FILE))
+    }
+
+    fn load_blocks(&mut self) -> io::Result<Vec<Block>> {
+        let path = self.dir.join(BLOCKS_FILE);
+        let bytes = read_if_exists(&path)?;
+
+        let mut chain = Vec::<Block>::new();
+        let mut legacy = false;
+        let mut pos = 0;
+        while let Some((payload, next)) = decode_record(&bytes, pos) {
+            // the checksum matched, so the record was written whole: a block that does not
+            // decode comes from another version of the node and must not be cut off as damage
+            let block = if payload.first() == Some(&b'{') {
+                legacy = true;
+                serde_json::from_slice::<Block>(payload).map_err(|e| e.to_string())
+            } else {
+                Block::deserialization(payload).map_err(|e| e.to_string())
+            };
+            let block = block.map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("block {} of {} cannot be decoded: {}", chain.len(), path.display(), e),
+                )
+            })?;
+            chain.push(block);
+            self.offsets.push(pos as u64);
+            pos = next;
+        }
+
+        // whatever follows the last good record was cut off by a crash
+        if pos < bytes.len() {
+            log::warn!(
+                "dropping {} trailing bytes of {}",
+                bytes.len() - pos,
+                path.display()
+            );
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(pos as u64)?;
+            file.sync_data()?;
+        }
+        self.end = pos as u64;
+
+        if legacy {
+            log::info!("migrating {} to the binary block format", path.display());
+            self.rewrite_blocks(&chain)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Replace blocks.dat with `chain` written in the current format
+    fn rewrite_blocks(&mut self, chain: &[Block]) -> io::Result<()> {
+        let mut bin = Vec::<u8>::new();
+        let mut offsets = Vec::<u64>::with_capacity(chain.len());
+        for block in chain {
+            offsets.push(bin.len() as u64);
+            bin.extend(encode_record(&block.serialization())?);
+        }
+
+        let tmp_path = self.dir.join(BLOCKS_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bin)?;
+        file.sync_data()?;
+        fs::rename(tmp_path, self.dir.join(BLOCKS_FILE))?;
+
+        self.offsets = offsets;
+        self.end = bin.len() as u64;
+        Ok(())
+    }
+
+    fn load_pool(&self) -> io::Result<Vec<Vec<u8>>> {
+        let bytes = read_if_exists(&self.dir.join(MEMPOOL_FILE))?;
+
+        let mut transaction_pool = Vec::<Vec<u8>>::new();
+        let mut pos = 0;
+        while let Some((payload, next)) = decode_record(&bytes, pos) {
+            transaction_pool.push(payload.to_vec());
+            pos = next;
+        }
+
+        Ok(transaction_pool)
+    }
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::<u8>::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(bytes),
+        Err(e) => Err(e),
+    }
+}
+
+fn encode_record(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+
+    let mut record = Vec::<u8>::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend(len.to_be_bytes());
+    record.extend(Sha256::digest(payload));
+    record.extend(payload);
+    Ok(record)
+}
+
+/// Decode the record starting at `pos`, returning its payload and the offset of the next record
+fn decode_record(bytes: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let header = bytes.get(pos..pos + RECORD_HEADER_LEN)?;
+    let len = u32::from_be_bytes(header[0..4].try_into().ok()?) as usize;
+    let checksum = &header[4..];
+
+    let start = pos + RECORD_HEADER_LEN;
+    let payload = bytes.get(start..start + len)?;
+    if Sha256::digest(payload).as_slice() != checksum {
+        return None;
+    }
+
+    Some((payload, start + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test, tests run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn stored(dir: &Path, count: u64) -> Vec<Block> {
+        let (mut storage, _, _) = Storage::open(dir).unwrap();
+        let blocks: Vec<Block> = (0..count)
+            .map(|nonce| Block::new(nonce, vec![nonce as u8; 32]))
+            .collect();
+        for block in &blocks {
+            storage.append_block(block).unwrap();
+        }
+        blocks
+    }
+
+    fn append_bytes(dir: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCKS_FILE))
+            .unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn blocks_survive_a_reopen() {
+        let dir = temp_dir("reopen");
+        let blocks = stored(&dir, 3);
+
+        let (storage, chain, pool) = Storage::open(&dir).unwrap();
+        assert_eq!(chain, blocks);
+        assert!(pool.is_empty());
+        assert_eq!(storage.offsets.len(), 3);
+        assert_eq!(storage.end, fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let dir = temp_dir("torn-tail");
+        let blocks = stored(&dir, 2);
+        let good_len = fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len();
+
+        // a crash half way through the third record
+        let record = encode_record(&Block::new(9, vec![9; 32]).serialization()).unwrap();
+        append_bytes(&dir, &record[..record.len() / 2]);
+
+        let (mut storage, chain, _) = Storage::open(&dir).unwrap();
+        assert_eq!(chain, blocks);
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), good_len);
+
+        // and appending carries on from the last good record
+        let block = Block::new(3, vec![3; 32]);
+        storage.append_block(&block).unwrap();
+        let (_, chain, _) = Storage::open(&dir).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[2], block);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_append_is_written_over() {
+        let dir = temp_dir("failed-append");
+        let mut blocks = stored(&dir, 2);
+        let (mut storage, _, _) = Storage::open(&dir).unwrap();
+
+        // what a write that failed part way leaves behind, longer than the next record
+        append_bytes(&dir, &[0xff; 4096]);
+
+        let block = Block::new(3, vec![3; 32]);
+        storage.append_block(&block).unwrap();
+        blocks.push(block);
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), storage.end);
+
+        let (_, chain, _) = Storage::open(&dir).unwrap();
+        assert_eq!(chain, blocks);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn undecodable_block_is_an_error() {
+        let dir = temp_dir("undecodable");
+        stored(&dir, 2);
+
+        // whole and checksummed, but not a block this node can read
+        append_bytes(&dir, &encode_record(&[0xee, 1, 2, 3]).unwrap());
+        let len = fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len();
+
+        let err = Storage::open(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), len);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_drops_the_tail() {
+        let dir = temp_dir("truncate");
+        let blocks = stored(&dir, 4);
+
+        let (mut storage, _, _) = Storage::open(&dir).unwrap();
+        storage.truncate(2).unwrap();
+        let block = Block::new(7, vec![7; 32]);
+        storage.append_block(&block).unwrap();
+
+        let (_, chain, _) = Storage::open(&dir).unwrap();
+        assert_eq!(chain, [blocks[0].clone(), blocks[1].clone(), block]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,19 +1,78 @@
 use crate::blockchain::*;
+use crate::blockchain::amount::{Amount, AmountError};
+use crate::blockchain::codec::{self, CodecError, Reader, Writer};
 use std::fmt;
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Transaction {
    pub sender_address : Vec<u8>,
    pub recipient_address :Vec<u8>,
-   pub value : f64,
+   pub value : Amount,
+   pub fee : Amount,
+   pub nonce : u64,
+   pub public_key : Vec<u8>,
+   pub signature : Vec<u8>,
 }
+/// Where a transaction is in its life, as reported by its receipt
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Pending,
+    Confirmed {
+        block_height: usize,
+        #[serde(with = "hex::serde")]
+        block_hash: Vec<u8>,
+        confirmations: usize,
+    },
+}
+
 impl  Transaction {
-    pub fn new(sender:Vec<u8>, receipient:Vec<u8>,value:f64)-> Transaction{
+    pub fn new(sender:Vec<u8>, receipient:Vec<u8>,value:Amount)-> Transaction{
         Transaction {
              sender_address: sender,
              recipient_address: receipient, 
-             value
+             value,
+             fee: Amount::ZERO,
+             nonce: 0,
+             public_key: Vec::new(),
+             signature: Vec::new(),
             }
     }
+
+    /// What the sender pays: the value plus the fee
+    pub fn total_spent(&self) -> Result<Amount, AmountError> {
+        self.value.checked_add(self.fee)
+    }
+
+    /// SHA256 of the serialized transaction, used as its id
+    pub fn hash(&self) -> Vec<u8> {
+        Sha256::digest(self.serialization()).to_vec()
+    }
+
+    /// Keep the signed wallet transaction so the block can be re-verified later
+    pub fn from_wallet_transaction(tx: &WalletTransaction) -> Transaction {
+        Transaction {
+            sender_address: tx.sender.as_bytes().to_vec(),
+            recipient_address: tx.recipient.as_bytes().to_vec(),
+            value: tx.amount,
+            fee: tx.fee,
+            nonce: tx.nonce,
+            public_key: tx.public_key.as_bytes().to_vec(),
+            signature: tx.signature.as_bytes().to_vec(),
+        }
+    }
+
+    /// Rebuild the wallet transaction that was signed
+    pub fn to_wallet_transaction(&self) -> WalletTransaction {
+        WalletTransaction {
+            sender: String::from_utf8_lossy(&self.sender_address).into_owned(),
+            recipient: String::from_utf8_lossy(&self.recipient_address).into_owned(),
+            amount: self.value,
+            fee: self.fee,
+            nonce: self.nonce,
+            public_key: String::from_utf8_lossy(&self.public_key).into_owned(),
+            signature: String::from_utf8_lossy(&self.signature).into_owned(),
+        }
+    }
 }
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -22,62 +81,81 @@ impl fmt::Display for Transaction {
 
         write!(
             f,
-            "{}\nSender address : {:?}\nRecipient address : {:?}\nValue : {}\n{}",
+            "{}\nSender address : {:?}\nRecipient address : {:?}\nValue : {}\nFee : {}\nNonce : {}\n{}",
             separator,
             self.sender_address,
             self.recipient_address,
             self.value,
+            self.fee,
+            self.nonce,
             separator
         )
     }
 }
 
-impl Serialization<Transaction> for Transaction {
-    fn deserialization(bytes: Vec<u8>) -> Transaction {
-        let mut pos = 0;
-
-        // Deserialize sender_address
-        let len_sender = usize::from_be_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let sender_address = bytes[pos..pos+len_sender].to_vec();
-        pos += len_sender;
+impl Transaction {
+    /// Decode the unversioned format of the first nodes: sender, recipient and a value in coins
+    ///
+    /// Those transactions were neither signed nor numbered, and paid no fee. The f64 value is
+    /// read as the decimal it prints as, which is what the node showed for it, so `0.1`
+    /// stays exactly 0.1 coins; values that are no amount at all are refused.
+    fn legacy_deserialization(bytes: &[u8]) -> Result<Transaction, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let field = |reader: &mut Reader, name: &'static str, max: usize| {
+            let len = reader.u64()?;
+            reader.sized(name, len, max)
+        };
 
-        // Deserialize recipient_address
-        let len_recipient = usize::from_be_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let recipient_address = bytes[pos..pos+len_recipient].to_vec();
-        pos += len_recipient;
+        let sender_address = field(&mut reader, "sender address", codec::MAX_ADDRESS_LEN)?;
+        let recipient_address = field(&mut reader, "recipient address", codec::MAX_ADDRESS_LEN)?;
+        let len = reader.u64()?;
+        if len != 8 {
+            return Err(CodecError::InvalidLength { field: "value", len });
+        }
+        let value = f64::from_bits(reader.u64()?);
+        reader.finish()?;
 
-        // Deserialize value (assuming it's u64)
-        let len_value = usize::from_be_bytes(bytes[pos..pos+8].try_into().unwrap());
-        pos += 8;
-        let value = f64::from_be_bytes(bytes[pos..pos+len_value].try_into().unwrap());
-       
+        let value = value
+            .to_string()
+            .parse::<Amount>()
+            .map_err(|_| CodecError::InvalidValue { field: "value" })?;
 
-        Transaction {
-            sender_address,
-            recipient_address,
-            value,
-        }
+        Ok(Transaction::new(sender_address, recipient_address, value))
     }
+}
 
-    fn serialization(&self) -> Vec<u8> {
-        let mut bin = Vec::<u8>::new();
-
-        let len_sender = self.sender_address.len() as u64;
-        bin.extend(&len_sender.to_be_bytes());
-        bin.extend(&self.sender_address);
+impl Serialization<Transaction> for Transaction {
+    fn deserialization(bytes: &[u8]) -> Result<Transaction, CodecError> {
+        let mut reader = Reader::new(bytes);
+        match reader.u8()? {
+            codec::LEGACY_VERSION => return Transaction::legacy_deserialization(bytes),
+            codec::VERSION => {}
+            version => return Err(CodecError::UnsupportedVersion(version)),
+        }
 
-        let len_recipient = self.recipient_address.len() as u64;
-        bin.extend(&len_recipient.to_be_bytes());
-        bin.extend(&self.recipient_address);
+        let transaction = Transaction {
+            sender_address: reader.bytes("sender address", codec::MAX_ADDRESS_LEN)?,
+            recipient_address: reader.bytes("recipient address", codec::MAX_ADDRESS_LEN)?,
+            value: Amount::from_units(reader.u64()?),
+            fee: Amount::from_units(reader.u64()?),
+            nonce: reader.u64()?,
+            public_key: reader.bytes("public key", codec::MAX_KEY_LEN)?,
+            signature: reader.bytes("signature", codec::MAX_KEY_LEN)?,
+        };
+        reader.finish()?;
 
-        let value_bytes = self.value.to_be_bytes();
-        let len_value = value_bytes.len() as u64;
-        bin.extend(&len_value.to_be_bytes());
-        bin.extend(&value_bytes);
+        Ok(transaction)
+    }
 
-        bin
+    fn serialization(&self) -> Vec<u8> {
+        Writer::new(codec::VERSION)
+            .bytes(&self.sender_address)
+            .bytes(&self.recipient_address)
+            .u64(self.value.units())
+            .u64(self.fee.units())
+            .u64(self.nonce)
+            .bytes(&self.public_key)
+            .bytes(&self.signature)
+            .finish()
     }
 }
-    
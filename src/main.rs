@@ -2,7 +2,6 @@ pub mod apiserver;
 use crate::apiserver::ApiServer;
 pub mod blockchain;
 pub mod wallet;
-use actix_web::middleware::Logger;
 use std::thread;
 
 /*
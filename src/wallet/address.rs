@@ -0,0 +1,259 @@
+use crate::blockchain::network::Network;
+use p256::ecdsa::VerifyingKey;
+use ripemd160::{Digest as RipDigest, Ripemd160};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+/*
+A blockchain address is the Base58Check encoding of a version byte and the
+hash of a public key:
+
+1.  do sha256 hash on the x, y of the public key
+2.  do ripemd160 hash on the result of step 1, giving 20 bytes
+3.  add the version byte of the network at the head of the result of step 2
+4.  do sha256 hash on the result of step 3
+5.  do sha256 hash on the result of step 4
+6.  take the first 4 bytes of the result of step 5 as checksum
+7.  append the checksum to the end of the result of step 3
+8.  encode the result of step 7 in base58
+
+Parsing runs the steps backwards, so a mistyped character fails the checksum
+instead of naming an address nobody holds the key of, and the version byte
+tells which network the address belongs to.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Address {
+    version: u8,
+    hash: [u8; 20],
+}
+
+/// Reason a string is not an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    InvalidBase58,
+    InvalidLength(usize),
+    InvalidChecksum,
+    UnknownVersion(u8),
+    WrongNetwork { expected: Network, found: Network },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::InvalidBase58 => write!(f, "address is not base58"),
+            AddressError::InvalidLength(len) => {
+                write!(f, "address decodes to {} bytes, 25 expected", len)
+            }
+            AddressError::InvalidChecksum => {
+                write!(f, "address checksum does not match, it may be mistyped")
+            }
+            AddressError::UnknownVersion(version) => {
+                write!(f, "address version {} is not known", version)
+            }
+            AddressError::WrongNetwork { expected, found } => {
+                write!(f, "address belongs to {}, not {}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl Address {
+    const CHECKSUM_LEN: usize = 4;
+    const LEN: usize = 1 + 20 + Address::CHECKSUM_LEN;
+
+    pub fn from_verifying_key(verifying_key: &VerifyingKey, network: Network) -> Self {
+        let encoded = verifying_key.to_encoded_point(false);
+        // an uncompressed point always has both coordinates
+        let mut pub_key_bytes = encoded.x().map_or(vec![], |x| x.to_vec());
+        pub_key_bytes.extend(encoded.y().map_or(&[][..], |y| y.as_slice()));
+
+        // STEP 1 & 2 — SHA256 then RIPEMD160
+        let hash = Sha256::digest(&pub_key_bytes);
+        let mut hasher = Ripemd160::new();
+        hasher.update(hash);
+
+        Address {
+            version: network.params().address_version,
+            hash: hasher.finalize().into(),
+        }
+    }
+
+    /// Parse an address, refusing the ones of any network but `network`
+    pub fn parse_for(s: &str, network: Network) -> Result<Self, AddressError> {
+        let address: Address = s.parse()?;
+        match address.network() {
+            found if found == network => Ok(address),
+            found => Err(AddressError::WrongNetwork {
+                expected: network,
+                found,
+            }),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn network(&self) -> Network {
+        Network::from_address_version(self.version).expect("parsed addresses have a known version")
+    }
+
+    /// RIPEMD160 of the SHA256 of the public key
+    pub fn hash(&self) -> &[u8; 20] {
+        &self.hash
+    }
+
+    // STEP 4 to 6 — first 4 bytes of the double SHA256 of version || hash
+    fn checksum(payload: &[u8]) -> [u8; 4] {
+        let digest = Sha256::digest(Sha256::digest(payload));
+        [digest[0], digest[1], digest[2], digest[3]]
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // STEP 3, 7 & 8
+        let mut payload = Vec::with_capacity(Address::LEN);
+        payload.push(self.version);
+        payload.extend(self.hash);
+        let checksum = Address::checksum(&payload);
+        payload.extend(checksum);
+        write!(f, "{}", bs58::encode(payload).into_string())
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| AddressError::InvalidBase58)?;
+        if bytes.len() != Address::LEN {
+            return Err(AddressError::InvalidLength(bytes.len()));
+        }
+
+        let (payload, checksum) = bytes.split_at(Address::LEN - Address::CHECKSUM_LEN);
+        if Address::checksum(payload) != checksum {
+            return Err(AddressError::InvalidChecksum);
+        }
+        if Network::from_address_version(payload[0]).is_none() {
+            return Err(AddressError::UnknownVersion(payload[0]));
+        }
+
+        Ok(Address {
+            version: payload[0],
+            hash: payload[1..].try_into().expect("payload is version and 20 bytes"),
+        })
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = AddressError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(address: Address) -> Self {
+        address.to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    // Base58Check of version || hash with a valid checksum
+    fn encode(version: u8, hash: [u8; 20]) -> String {
+        let mut payload = vec![version];
+        payload.extend(hash);
+        let checksum = Address::checksum(&payload);
+        payload.extend(checksum);
+        bs58::encode(payload).into_string()
+    }
+
+    #[test]
+    fn encodes_like_bitcoin() {
+        // the well known all zero mainnet address
+        let address = Address {
+            version: 0,
+            hash: [0; 20],
+        };
+        assert_eq!(address.to_string(), "1111111111111111111114oLvT2");
+        assert_eq!("1111111111111111111114oLvT2".parse(), Ok(address));
+    }
+
+    #[test]
+    fn round_trips_on_every_network() {
+        for (network, prefixes) in [
+            (Network::Mainnet, "1"),
+            (Network::Testnet, "mn"),
+            (Network::Devnet, "D"),
+        ] {
+            let wallet = Wallet::new(network);
+            let address = Address::from_verifying_key(&wallet.verifying_key, network);
+            let text = address.to_string();
+            assert!(prefixes.contains(&text[..1]), "{} address {}", network, text);
+            assert_eq!(text.parse(), Ok(address));
+            assert_eq!(Address::parse_for(&text, network), Ok(address));
+            assert_eq!(address.network(), network);
+        }
+    }
+
+    #[test]
+    fn rejects_mistyped_addresses() {
+        let address = encode(0, [7; 20]);
+        let mut typo = address.clone().into_bytes();
+        let i = typo.len() / 2;
+        typo[i] = if typo[i] == b'2' { b'3' } else { b'2' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert_eq!(typo.parse::<Address>(), Err(AddressError::InvalidChecksum));
+
+        assert_eq!("0OIl".parse::<Address>(), Err(AddressError::InvalidBase58));
+        for len in [0, 24, 26] {
+            let text = bs58::encode(vec![1; len]).into_string();
+            assert_eq!(text.parse::<Address>(), Err(AddressError::InvalidLength(len)));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert_eq!(
+            encode(5, [7; 20]).parse::<Address>(),
+            Err(AddressError::UnknownVersion(5))
+        );
+        assert!(encode(0x6f, [7; 20]).parse::<Address>().is_ok());
+    }
+
+    #[test]
+    fn rejects_addresses_of_other_networks() {
+        let testnet = encode(0x6f, [7; 20]);
+        assert_eq!(
+            Address::parse_for(&testnet, Network::Mainnet),
+            Err(AddressError::WrongNetwork {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_as_a_checked_string() {
+        let address: Address = encode(0x1e, [9; 20]).parse().unwrap();
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, format!("\"{}\"", address));
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), address);
+
+        let bad = format!("\"{}\"", encode(5, [9; 20]));
+        assert!(serde_json::from_str::<Address>(&bad).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::blockchain::network::Network;
+use crate::wallet::{Wallet, WalletError};
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use p256::{
+    FieldBytes, NonZeroScalar, Scalar,
+    ecdsa::SigningKey,
+    elliptic_curve::{PrimeField, rand_core::{OsRng, RngCore}},
+};
+use sha2::Sha512;
+
+/*
+Hierarchical deterministic wallet.
+
+A BIP39 mnemonic of 12 to 24 words and an optional passphrase give a 64 byte
+seed, and every key of the wallet is derived from that seed, so the words are
+the only backup a user needs.
+
+BIP32 is defined on secp256k1 only, keys here are P-256, so derivation follows
+SLIP-10 for the nist256p1 curve, which is BIP32 with a curve specific master
+key and a retry when a derived scalar falls outside the curve order:
+
+    master:   I = HMAC-SHA512("Nist256p1 seed", seed)
+    hardened: I = HMAC-SHA512(chain code, 0x00 || k || index)
+    normal:   I = HMAC-SHA512(chain code, compressed public key || index)
+    child key = I[..32] + k mod n, child chain code = I[32..]
+
+Account N is m/44'/1'/N'/0/0: the BIP44 layout with coin type 1, the one
+reserved for test networks, and the first receiving address of the account.
+The same key gives one address per network, only the version byte differs.
+*/
+type HmacSha512 = Hmac<Sha512>;
+
+/// Index bit marking a hardened child, whose derivation needs the private key
+pub const HARDENED: u32 = 1 << 31;
+
+/// A private key with the chain code its children are derived with
+#[derive(Clone)]
+struct ExtendedKey {
+    key: NonZeroScalar,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    const MASTER_SECRET: &'static [u8] = b"Nist256p1 seed";
+
+    fn master(seed: &[u8]) -> Self {
+        let mut digest = hmac_sha512(ExtendedKey::MASTER_SECRET, &[seed]);
+        loop {
+            let (left, right) = digest.split_at(32);
+            let key: Option<NonZeroScalar> = NonZeroScalar::from_repr(*FieldBytes::from_slice(left)).into();
+            if let Some(key) = key {
+                return ExtendedKey {
+                    key,
+                    chain_code: right.try_into().expect("split at 32 of 64 bytes"),
+                };
+            }
+            // out of range, about one chance in 2^32
+            digest = hmac_sha512(ExtendedKey::MASTER_SECRET, &[&digest]);
+        }
+    }
+
+    fn child(&self, index: u32) -> Self {
+        let mut data = if index & HARDENED != 0 {
+            [&[0u8][..], &self.key.to_repr()].concat()
+        } else {
+            self.signing_key()
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec()
+        };
+        loop {
+            let digest = hmac_sha512(&self.chain_code, &[&data, &index.to_be_bytes()]);
+            let (left, right) = digest.split_at(32);
+            let tweak: Option<Scalar> = Scalar::from_repr(*FieldBytes::from_slice(left)).into();
+            let key = tweak.and_then(|tweak| Option::from(NonZeroScalar::new(tweak + *self.key)));
+            if let Some(key) = key {
+                return ExtendedKey {
+                    key,
+                    chain_code: right.try_into().expect("split at 32 of 64 bytes"),
+                };
+            }
+            data = [&[1u8][..], right].concat();
+        }
+    }
+
+    fn signing_key(&self) -> SigningKey {
+        SigningKey::from(self.key)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = HmacSha512::new_from_slice(key).expect("hmac accepts keys of any length");
+    for chunk in data {
+        mac.update(chunk);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Wallet whose keys all derive from one mnemonic
+pub struct HdWallet {
+    mnemonic: Mnemonic,
+    master: ExtendedKey,
+}
+
+impl HdWallet {
+    const PURPOSE: u32 = 44;
+    const COIN_TYPE: u32 = 1;
+
+    /// New wallet from a fresh random mnemonic of 12, 15, 18, 21 or 24 words
+    pub fn generate(word_count: usize) -> Result<Self, WalletError> {
+        if !(12..=24).contains(&word_count) || !word_count.is_multiple_of(3) {
+            return Err(WalletError::InvalidWordCount(word_count));
+        }
+        // every 3 words carry 32 bits of entropy and a 1 bit checksum
+        let mut entropy = vec![0u8; word_count / 3 * 4];
+        OsRng.fill_bytes(&mut entropy);
+        let mnemonic = Mnemonic::from_entropy(&entropy).map_err(|_| WalletError::InvalidMnemonic)?;
+        Ok(HdWallet::from_mnemonic(mnemonic, ""))
+    }
+
+    /// Wallet restored from an english mnemonic and the passphrase it was created with
+    pub fn from_phrase(phrase: &str, passphrase: &str) -> Result<Self, WalletError> {
+        let mnemonic = Mnemonic::parse(phrase).map_err(|e| match e {
+            bip39::Error::BadWordCount(count) => WalletError::InvalidWordCount(count),
+            _ => WalletError::InvalidMnemonic,
+        })?;
+        Ok(HdWallet::from_mnemonic(mnemonic, passphrase))
+    }
+
+    fn from_mnemonic(mnemonic: Mnemonic, passphrase: &str) -> Self {
+        let master = ExtendedKey::master(&mnemonic.to_seed(passphrase));
+        HdWallet { mnemonic, master }
+    }
+
+    /// The words to write down, separated by single spaces
+    pub fn phrase(&self) -> String {
+        self.mnemonic.to_string()
+    }
+
+    /// Wallet at `path`, a list of child indexes where hardened ones have `HARDENED` set
+    pub fn derive_path(&self, path: &[u32], network: Network) -> Wallet {
+        let key = path.iter().fold(self.master.clone(), |key, index| key.child(*index));
+        Wallet::from_signing_key(key.signing_key(), network)
+    }
+
+    /// Wallet of account `account`, at m/44'/1'/account'/0/0
+    pub fn derive_account(&self, account: u32, network: Network) -> Result<Wallet, WalletError> {
+        if account & HARDENED != 0 {
+            return Err(WalletError::InvalidAccount(account));
+        }
+        Ok(self.derive_path(&[
+            HdWallet::PURPOSE | HARDENED,
+            HdWallet::COIN_TYPE | HARDENED,
+            account | HARDENED,
+            0,
+            0,
+        ], network))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::address::Address;
+
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn derive(seed: &str, path: &[u32]) -> (String, String) {
+        let master = ExtendedKey::master(&hex::decode(seed).unwrap());
+        let key = path.iter().fold(master, |key, index| key.child(*index));
+        (hex::encode(key.chain_code), hex::encode(key.key.to_repr()))
+    }
+
+    // SLIP-10 test vector 1 for nist256p1, chain code then private key
+    #[test]
+    fn slip10_vector_1() {
+        let vectors: [(&[u32], &str, &str); 6] = [
+            (
+                &[],
+                "beeb672fe4621673f722f38529c07392fecaa61015c80c34f29ce8b41b3cb6ea",
+                "612091aaa12e22dd2abef664f8a01a82cae99ad7441b7ef8110424915c268bc2",
+            ),
+            (
+                &[HARDENED],
+                "3460cea53e6a6bb5fb391eeef3237ffd8724bf0a40e94943c98b83825342ee11",
+                "6939694369114c67917a182c59ddb8cafc3004e63ca5d3b84403ba8613debc0c",
+            ),
+            (
+                &[HARDENED, 1],
+                "4187afff1aafa8445010097fb99d23aee9f599450c7bd140b6826ac22ba21d0c",
+                "284e9d38d07d21e4e281b645089a94f4cf5a5a81369acf151a1c3a57f18b2129",
+            ),
+            (
+                &[HARDENED, 1, 2 | HARDENED],
+                "98c7514f562e64e74170cc3cf304ee1ce54d6b6da4f880f313e8204c2a185318",
+                "694596e8a54f252c960eb771a3c41e7e32496d03b954aeb90f61635b8e092aa7",
+            ),
+            (
+                &[HARDENED, 1, 2 | HARDENED, 2],
+                "ba96f776a5c3907d7fd48bde5620ee374d4acfd540378476019eab70790c63a0",
+                "5996c37fd3dd2679039b23ed6f70b506c6b56b3cb5e424681fb0fa64caf82aaa",
+            ),
+            (
+                &[HARDENED, 1, 2 | HARDENED, 2, 1_000_000_000],
+                "b9b7b82d326bb9cb5b5b121066feea4eb93d5241103c9e7a18aad40f1dde8059",
+                "21c4f269ef0a5fd1badf47eeacebeeaa3de22eb8e5b0adcd0f27dd99d34d0119",
+            ),
+        ];
+        for (path, chain_code, private_key) in vectors {
+            assert_eq!(derive(SEED, path), (chain_code.to_string(), private_key.to_string()), "{:?}", path);
+        }
+    }
+
+    // SLIP-10 derivation retry vector, the first scalar of m/28578' falls outside the curve order
+    #[test]
+    fn slip10_retry() {
+        assert_eq!(
+            derive(SEED, &[28578 | HARDENED]),
+            (
+                "e94c8ebe30c2250a14713212f6449b20f3329105ea15b652ca5bdfc68f6c65c2".to_string(),
+                "06f0db126f023755d0b8d86d4591718a5210dd8d024e3e14b6159d63f53aa669".to_string(),
+            )
+        );
+        assert_eq!(
+            derive(SEED, &[28578 | HARDENED, 33941]),
+            (
+                "9e87fe95031f14736774cd82f25fd885065cb7c358c1edf813c72af535e83071".to_string(),
+                "092154eed4af83e078ff9b84322015aefe5769e31270f62c3f66c33888335f3a".to_string(),
+            )
+        );
+    }
+
+    #[test]
+    fn phrase_restores_the_same_accounts() {
+        let wallet = HdWallet::generate(24).unwrap();
+        assert_eq!(wallet.phrase().split(' ').count(), 24);
+
+        let restored = HdWallet::from_phrase(&wallet.phrase(), "").unwrap();
+        for account in [0, 1, HARDENED - 1] {
+            let a = wallet.derive_account(account, Network::Mainnet).unwrap();
+            let b = restored.derive_account(account, Network::Mainnet).unwrap();
+            assert_eq!(a.private_key_str(), b.private_key_str());
+        }
+        let first = wallet.derive_account(0, Network::Mainnet).unwrap();
+        let second = wallet.derive_account(1, Network::Mainnet).unwrap();
+        assert_ne!(first.private_key_str(), second.private_key_str());
+    }
+
+    #[test]
+    fn passphrase_gives_another_wallet() {
+        let plain = HdWallet::from_phrase(PHRASE, "").unwrap();
+        let protected = HdWallet::from_phrase(PHRASE, "TREZOR").unwrap();
+        assert_ne!(
+            plain.derive_account(0, Network::Mainnet).unwrap().private_key_str(),
+            protected.derive_account(0, Network::Mainnet).unwrap().private_key_str()
+        );
+    }
+
+    #[test]
+    fn one_key_per_account_on_every_network() {
+        let wallet = HdWallet::from_phrase(PHRASE, "").unwrap();
+        let mainnet = wallet.derive_account(0, Network::Mainnet).unwrap();
+        let devnet = wallet.derive_account(0, Network::Devnet).unwrap();
+        assert_eq!(mainnet.private_key_str(), devnet.private_key_str());
+
+        let mainnet: Address = mainnet.get_adress().parse().unwrap();
+        let devnet: Address = devnet.get_adress().parse().unwrap();
+        assert_eq!(mainnet.hash(), devnet.hash());
+        assert_eq!(devnet.network(), Network::Devnet);
+    }
+
+    #[test]
+    fn rejects_bad_mnemonics() {
+        for words in [0, 11, 13, 27] {
+            assert!(matches!(HdWallet::generate(words), Err(WalletError::InvalidWordCount(count)) if count == words));
+        }
+        // "abandon" twelve times fails the checksum
+        let bad_checksum = ["abandon"; 12].join(" ");
+        assert!(matches!(HdWallet::from_phrase(&bad_checksum, ""), Err(WalletError::InvalidMnemonic)));
+        let unknown_word = PHRASE.replace("about", "aboot");
+        assert!(matches!(HdWallet::from_phrase(&unknown_word, ""), Err(WalletError::InvalidMnemonic)));
+        assert!(matches!(
+            HdWallet::from_phrase("abandon about", ""),
+            Err(WalletError::InvalidWordCount(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_hardened_account_numbers() {
+        let wallet = HdWallet::from_phrase(PHRASE, "").unwrap();
+        assert!(matches!(
+            wallet.derive_account(HARDENED, Network::Mainnet),
+            Err(WalletError::InvalidAccount(HARDENED))
+        ));
+    }
+}
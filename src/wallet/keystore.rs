@@ -0,0 +1,329 @@
+use crate::wallet::{Wallet, WalletError, address::Address};
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use p256::{
+    ecdsa::SigningKey,
+    elliptic_curve::rand_core::{OsRng, RngCore},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, io::Write, path::Path};
+
+/*
+Password protected wallet file.
+
+The private key never touches the disk in clear: a 32 byte key is stretched
+from the password with scrypt and a random salt, and the private key is
+sealed with AES-256-GCM under it. The address is stored in clear so a
+keystore can be told apart without its password, and is bound to the
+ciphertext as associated data so it cannot be swapped for another one.
+
+A wrong password and a tampered file look the same, the GCM tag does not
+match, and are both reported as `WrongPassword`.
+*/
+#[derive(Serialize, Deserialize, Debug)]
+struct KeystoreFile {
+    version: u8,
+    address: String,
+    kdf: ScryptParams,
+    // 12 byte AES-GCM nonce
+    #[serde(with = "hex::serde")]
+    nonce: Vec<u8>,
+    // the private key followed by the 16 byte tag
+    #[serde(with = "hex::serde")]
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ScryptParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    #[serde(with = "hex::serde")]
+    salt: Vec<u8>,
+}
+
+/// Reason a keystore could not be written or opened
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Malformed(String),
+    UnsupportedVersion(u8),
+    WrongPassword,
+    Key(WalletError),
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore i/o failed: {}", e),
+            KeystoreError::Malformed(reason) => write!(f, "malformed keystore: {}", reason),
+            KeystoreError::UnsupportedVersion(version) => {
+                write!(f, "unsupported keystore version {}", version)
+            }
+            KeystoreError::WrongPassword => {
+                write!(f, "wrong password or corrupted keystore")
+            }
+            KeystoreError::Key(e) => write!(f, "keystore holds an invalid key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl KeystoreFile {
+    const VERSION: u8 = 1;
+    // 2^15 rounds take 32 MiB and a fraction of a second
+    const LOG_N: u8 = 15;
+    const R: u32 = 8;
+    const P: u32 = 1;
+    // refused when loading, so a crafted file cannot ask for gigabytes
+    const MAX_LOG_N: u8 = 20;
+    const SALT_LEN: usize = 32;
+    const NONCE_LEN: usize = 12;
+
+    fn seal(wallet: &Wallet, password: &str) -> Result<Self, KeystoreError> {
+        let mut salt = vec![0u8; KeystoreFile::SALT_LEN];
+        let mut nonce = vec![0u8; KeystoreFile::NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let kdf = ScryptParams {
+            log_n: KeystoreFile::LOG_N,
+            r: KeystoreFile::R,
+            p: KeystoreFile::P,
+            salt,
+        };
+        let address = wallet.get_adress();
+        let ciphertext = kdf
+            .cipher(password)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &wallet.signing_key.to_bytes(),
+                    aad: address.as_bytes(),
+                },
+            )
+            .map_err(|_| KeystoreError::Malformed("encryption failed".to_string()))?;
+
+        Ok(KeystoreFile {
+            version: KeystoreFile::VERSION,
+            address,
+            kdf,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn open(&self, password: &str) -> Result<Wallet, KeystoreError> {
+        if self.version != KeystoreFile::VERSION {
+            return Err(KeystoreError::UnsupportedVersion(self.version));
+        }
+        if self.nonce.len() != KeystoreFile::NONCE_LEN {
+            return Err(KeystoreError::Malformed(format!(
+                "nonce is {} bytes, {} expected",
+                self.nonce.len(),
+                KeystoreFile::NONCE_LEN
+            )));
+        }
+
+        let private_key = self
+            .kdf
+            .cipher(password)?
+            .decrypt(
+                Nonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: self.address.as_bytes(),
+                },
+            )
+            .map_err(|_| KeystoreError::WrongPassword)?;
+
+        let signing_key = SigningKey::from_slice(&private_key)
+            .map_err(|_| KeystoreError::Key(WalletError::InvalidPrivateKey))?;
+        let address: Address = self
+            .address
+            .parse()
+            .map_err(|e| KeystoreError::Key(WalletError::InvalidAddress(e)))?;
+        let wallet = Wallet::from_signing_key(signing_key, address.network());
+        if wallet.get_adress() != self.address {
+            return Err(KeystoreError::Key(WalletError::AddressMismatch));
+        }
+        Ok(wallet)
+    }
+}
+
+impl ScryptParams {
+    /// AES-256-GCM keyed with the password stretched by these parameters
+    fn cipher(&self, password: &str) -> Result<Aes256Gcm, KeystoreError> {
+        if self.log_n > KeystoreFile::MAX_LOG_N {
+            return Err(KeystoreError::Malformed(format!(
+                "scrypt log_n {} is over {}",
+                self.log_n,
+                KeystoreFile::MAX_LOG_N
+            )));
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|e| KeystoreError::Malformed(format!("scrypt parameters: {}", e)))?;
+
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), &self.salt, &params, &mut key)
+            .map_err(|e| KeystoreError::Malformed(format!("scrypt: {}", e)))?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+impl Wallet {
+    /// Write the wallet to a new keystore file at `path`, encrypted with `password`
+    ///
+    /// An existing file is never overwritten, it may be the only copy of another key.
+    /// The file is written next to `path` first and only linked into place once it is
+    /// on disk, so a crash never leaves a half written keystore behind.
+    pub fn save_encrypted(&self, path: impl AsRef<Path>, password: &str) -> Result<(), KeystoreError> {
+        let path = path.as_ref();
+        let keystore = KeystoreFile::seal(self, password)?;
+        let json = serde_json::to_vec_pretty(&keystore)
+            .map_err(|e| KeystoreError::Malformed(e.to_string()))?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut options = fs::OpenOptions::new();
+        // a temporary file left by a crash is simply written over
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+
+        // unlike a rename, a hard link fails when `path` exists instead of replacing it
+        let linked = fs::hard_link(&tmp_path, path);
+        fs::remove_file(&tmp_path)?;
+        linked?;
+        // the new directory entry only survives a crash once the directory is synced
+        #[cfg(unix)]
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Read back a wallet written by `save_encrypted`
+    pub fn load_encrypted(path: impl AsRef<Path>, password: &str) -> Result<Wallet, KeystoreError> {
+        let json = fs::read(path)?;
+        let keystore: KeystoreFile =
+            serde_json::from_slice(&json).map_err(|e| KeystoreError::Malformed(e.to_string()))?;
+        keystore.open(password)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::network::Network;
+    use std::path::PathBuf;
+
+    // a fresh directory per test, tests run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("keystore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = temp_dir("round-trip");
+        let path = dir.join("miner.json");
+        let wallet = Wallet::new(Network::Testnet);
+        wallet.save_encrypted(&path, "correct horse").unwrap();
+
+        let loaded = Wallet::load_encrypted(&path, "correct horse").unwrap();
+        assert_eq!(loaded.private_key_str(), wallet.private_key_str());
+        assert_eq!(loaded.get_adress(), wallet.get_adress());
+        assert_eq!(loaded.network(), Network::Testnet);
+
+        // the key is not stored in clear and the temporary file is gone
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(!json.contains(&wallet.private_key_str()));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_password_is_refused() {
+        let dir = temp_dir("wrong-password");
+        let path = dir.join("miner.json");
+        Wallet::new(Network::Mainnet).save_encrypted(&path, "right").unwrap();
+
+        assert!(matches!(Wallet::load_encrypted(&path, "wrong"), Err(KeystoreError::WrongPassword)));
+        assert!(matches!(Wallet::load_encrypted(&path, ""), Err(KeystoreError::WrongPassword)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn existing_keystore_is_not_overwritten() {
+        let dir = temp_dir("overwrite");
+        let path = dir.join("miner.json");
+        let first = Wallet::new(Network::Mainnet);
+        first.save_encrypted(&path, "password").unwrap();
+
+        let result = Wallet::new(Network::Mainnet).save_encrypted(&path, "password");
+        assert!(matches!(result, Err(KeystoreError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        let loaded = Wallet::load_encrypted(&path, "password").unwrap();
+        assert_eq!(loaded.private_key_str(), first.private_key_str());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn swapped_address_is_refused() {
+        let wallet = Wallet::new(Network::Mainnet);
+        let mut keystore = KeystoreFile::seal(&wallet, "password").unwrap();
+        // the address is bound to the ciphertext, it cannot be pointed at another one
+        keystore.address = Wallet::new(Network::Mainnet).get_adress();
+        assert!(matches!(keystore.open("password"), Err(KeystoreError::WrongPassword)));
+    }
+
+    #[test]
+    fn malformed_keystores_are_refused() {
+        let wallet = Wallet::new(Network::Mainnet);
+        let sealed = || KeystoreFile::seal(&wallet, "password").unwrap();
+
+        let mut keystore = sealed();
+        keystore.version = 2;
+        assert!(matches!(keystore.open("password"), Err(KeystoreError::UnsupportedVersion(2))));
+
+        let mut keystore = sealed();
+        keystore.kdf.log_n = KeystoreFile::MAX_LOG_N + 1;
+        assert!(matches!(keystore.open("password"), Err(KeystoreError::Malformed(_))));
+
+        let mut keystore = sealed();
+        keystore.nonce.pop();
+        assert!(matches!(keystore.open("password"), Err(KeystoreError::Malformed(_))));
+
+        let dir = temp_dir("malformed");
+        let path = dir.join("miner.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "{\"version\": 1").unwrap();
+        assert!(matches!(Wallet::load_encrypted(&path, "password"), Err(KeystoreError::Malformed(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,17 +1,23 @@
-use log::debug;
 use p256::{
     ecdsa::{Signature, SigningKey, VerifyingKey, signature::Signer, signature::Verifier},
     elliptic_curve::rand_core::OsRng,
 };
-use sha2::{Sha256, Digest};
-use ripemd160::{Ripemd160, Digest as RipDigest};
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::blockchain::amount::Amount;
+use crate::blockchain::network::Network;
+use address::{Address, AddressError};
+
+pub mod address;
+pub mod hd;
+pub mod keystore;
 
 
 pub struct Wallet {
     pub signing_key: SigningKey,
     pub verifying_key: VerifyingKey,
     address: String,
+    network: Network,
 }
 
 #[derive(Serialize , Debug ,Clone)]
@@ -22,35 +28,75 @@ pub struct  WalletData {
 
 
 }
-#[derive(Serialize, Clone, Debug)]
+/// Reason key material could not be turned into a wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletError {
+    InvalidPublicKey,
+    InvalidPrivateKey,
+    KeyMismatch,
+    InvalidAddress(AddressError),
+    AddressMismatch,
+    InvalidMnemonic,
+    InvalidWordCount(usize),
+    InvalidAccount(u32),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::InvalidPublicKey => write!(f, "public key is not a hex encoded p256 point"),
+            WalletError::InvalidPrivateKey => {
+                write!(f, "private key is not a hex encoded 32 byte p256 scalar")
+            }
+            WalletError::KeyMismatch => write!(f, "public key does not belong to the private key"),
+            WalletError::InvalidAddress(e) => write!(f, "{}", e),
+            WalletError::AddressMismatch => {
+                write!(f, "blockchain address does not belong to the key")
+            }
+            WalletError::InvalidMnemonic => {
+                write!(f, "mnemonic has an unknown word or a wrong checksum")
+            }
+            WalletError::InvalidWordCount(count) => {
+                write!(f, "mnemonic cannot have {} words, 12, 15, 18, 21 or 24 expected", count)
+            }
+            WalletError::InvalidAccount(account) => {
+                write!(f, "account {} is out of range, accounts go up to 2^31 - 1", account)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub sender: String,
     pub recipient: String,
-    pub amount: f64,
+    pub amount: Amount,
+    // paid to the miner of the block that includes the transaction
+    pub fee: Amount,
+    // sequence number of the sender, one more than its previous transaction
+    pub nonce: u64,
     pub public_key: String,
     pub signature: String,
 }
 
-/*
-1.  do sha256  hash  on the x ,y of public key
-2.  do ripemd  hash on the result of strp 1 and we will get 20 bytes result
-3.  add a version byte at the head  of the result form step 2 (0x00 for mainnet)
-4.  do sha 256 hash on the result  of step3
-5.  do sha256 hash on the result of step 4
-6.  take the first 4 bute of result of step 5 as checksu,
-7.  appen the 4 bytes form strp 6 to the end  of the result from step3
-8. encode the result of step 7 by baser 58
-*/
-
 impl Wallet {
- pub fn new_from(public_key_str: &String, private_key_str: &String, address: &String) -> Self {
+    /// Wallet from hex encoded keys, checking that the keys and the address belong together
+    pub fn new_from(
+        public_key_str: &str,
+        private_key_str: &str,
+        address: &str,
+    ) -> Result<Self, WalletError> {
         //convert the public string to VerifyingKey
-        //Result<[u8], None>
-        let mut public_key_bin = hex::decode(public_key_str).unwrap();
+        let mut public_key_bin =
+            hex::decode(public_key_str).map_err(|_| WalletError::InvalidPublicKey)?;
         public_key_bin.insert(0, 0x04);
-        let verifying_key = VerifyingKey::from_sec1_bytes(&public_key_bin).unwrap();
+        let verifying_key = VerifyingKey::from_sec1_bytes(&public_key_bin)
+            .map_err(|_| WalletError::InvalidPublicKey)?;
 
-        let private_key_bytes = hex::decode(&private_key_str).unwrap();
+        let private_key_bytes =
+            hex::decode(private_key_str).map_err(|_| WalletError::InvalidPrivateKey)?;
         /*
         1, the binary data of private key should be 32 bytes,
         2, private_key_bytes is Vec<u8>, convert it to [u8;32], trait of try_into
@@ -59,66 +105,78 @@ impl Wallet {
         */
         let private_key_bytes: [u8; 32] = private_key_bytes
             .try_into()
-            .expect("Invalid private key hex data");
-        let signing_key = SigningKey::from_bytes((&private_key_bytes).into()).unwrap();
+            .map_err(|_| WalletError::InvalidPrivateKey)?;
+        let signing_key = SigningKey::from_bytes((&private_key_bytes).into())
+            .map_err(|_| WalletError::InvalidPrivateKey)?;
 
-        Wallet {
+        if *signing_key.verifying_key() != verifying_key {
+            return Err(WalletError::KeyMismatch);
+        }
+        // the address tells which network the wallet is for
+        let parsed: Address = address.parse().map_err(WalletError::InvalidAddress)?;
+        if Address::from_verifying_key(&verifying_key, parsed.network()) != parsed {
+            return Err(WalletError::AddressMismatch);
+        }
+
+        Ok(Wallet {
             verifying_key,
             signing_key,
-            address: address.clone(),
-        }
+            address: parsed.to_string(),
+            network: parsed.network(),
+        })
     }
 
-    
-    pub fn new() -> Self {
-        let signing_key = SigningKey::random(&mut OsRng);
-        let verifying_key = signing_key.verifying_key().clone();
-
-        // closure for generating address
-        let gen_address = || {
-            let encoded = verifying_key.to_encoded_point(false);
-
-            if let (Some(x), Some(y)) = (encoded.x(), encoded.y()) {
-                let mut pub_key_bytes = Vec::with_capacity(x.len() + y.len());
-                pub_key_bytes.extend_from_slice(x);
-                pub_key_bytes.extend_from_slice(y);
+    pub fn new(network: Network) -> Self {
+        Wallet::from_signing_key(SigningKey::random(&mut OsRng), network)
+    }
 
-                // STEP 1 — SHA256
-                let hash = Sha256::digest(&pub_key_bytes);
+    /// Wallet of account `account` of the HD wallet behind a mnemonic, see `hd`
+    pub fn from_mnemonic(
+        phrase: &str,
+        passphrase: &str,
+        account: u32,
+        network: Network,
+    ) -> Result<Self, WalletError> {
+        hd::HdWallet::from_phrase(phrase, passphrase)?.derive_account(account, network)
+    }
 
-                // STEP 2 — RIPEMD160 on the result of step1
-                let mut hasher = Ripemd160::new();
-                hasher.update(&hash);
-                let mut hash_result = hasher.finalize().to_vec();
+    pub fn from_signing_key(signing_key: SigningKey, network: Network) -> Self {
+        let verifying_key = *signing_key.verifying_key();
 
-                // STEP 3 — add byte version at the front of ripemd hash result (0x00 for main net)
-                hash_result.insert(0, 0x00);
+        let address = Wallet::address_from_verifying_key(&verifying_key, network);
 
-                // STEP 4 & 5 — double SHA256
-                let hash2 = Sha256::digest(&hash_result);
-                let hash3 = Sha256::digest(&hash2);
+        Self {
+            signing_key,
+            verifying_key,
+            address,
+            network,
+        }
+    }
 
-                // STEP 6 — take the first 4 bytes from previous result as checksum
-                let checksum = &hash3[0..4];
+    /// Blockchain address on `network` of a hex encoded `x || y` public key, `None` if the key is malformed
+    pub fn address_from_public_key(public_key_str: &str, network: Network) -> Option<String> {
+        let mut public_key_bin = hex::decode(public_key_str).ok()?;
+        public_key_bin.insert(0, 0x04); // sec1 format [0x04 || x || y]
+        let verifying_key = VerifyingKey::from_sec1_bytes(&public_key_bin).ok()?;
 
-                // STEP 7 — append checksum to the end of extended ripemd hash result
-                let full_hash = [hash_result, checksum.to_vec()].concat();
+        Some(Wallet::address_from_verifying_key(&verifying_key, network))
+    }
 
-                // STEP 8 — base58 encoding
-                bs58::encode(full_hash).into_string()
-            } else {
-                String::new()
-            }
+    /// Whether the transaction's public key derives its sender address
+    pub fn is_sender_key(transaction: &Transaction) -> bool {
+        let Ok(sender) = transaction.sender.parse::<Address>() else {
+            return false;
         };
+        Wallet::address_from_public_key(&transaction.public_key, sender.network()).as_ref()
+            == Some(&transaction.sender)
+    }
 
-        // call the closure to generate address
-        let address = gen_address();
+    fn address_from_verifying_key(verifying_key: &VerifyingKey, network: Network) -> String {
+        Address::from_verifying_key(verifying_key, network).to_string()
+    }
 
-        Self {
-            signing_key,
-            verifying_key,
-            address,
-        }
+    pub fn network(&self) -> Network {
+        self.network
     }
 
     pub fn get_wallet_data(&self) -> WalletData {
@@ -150,44 +208,162 @@ impl Wallet {
     }
 
     // SIGN A TRANSACTION
-    pub fn sign_transaction(&mut self, receiver: &String, amount: f64) -> Transaction {
+    /// Sign a transfer, `chain_id` being the hash of the genesis block of the chain it is meant for
+    pub fn sign_transaction(
+        &mut self,
+        receiver: &str,
+        amount: Amount,
+        fee: Amount,
+        nonce: u64,
+        chain_id: &[u8],
+    ) -> Transaction {
         let mut transaction = Transaction {
             sender: self.address.clone(),
-            recipient: receiver.clone(),
+            recipient: receiver.to_string(),
             amount,
+            fee,
+            nonce,
             signature: String::new(),
             public_key: self.public_key_str(),
         };
 
-        let serialize_str = serde_json::to_string(&transaction).unwrap();
-        let serialized = serialize_str.as_bytes();
+        // a struct of strings and integers always serializes
+        let serialized =
+            Wallet::signing_payload(&transaction, chain_id).expect("transaction serializes to json");
 
         // Sign using mutable reference to self.signing_key
-        let sig: Signature = self.signing_key.sign(serialized);
+        let sig: Signature = self.signing_key.sign(&serialized);
+        // p256 does not normalize, and verify_transaction only takes the low-S form
+        let sig = sig.normalize_s().unwrap_or(sig);
         transaction.signature = hex::encode(sig.to_bytes());
 
         transaction
     }
 
+    /*
+    bytes covered by the signature: the chain id followed by the json of the
+    transaction without its signature. Binding the chain id means a signature
+    made for one chain fails on any chain with another genesis block, so a
+    transaction cannot be replayed there.
+     */
+    fn signing_payload(transaction: &Transaction, chain_id: &[u8]) -> Option<Vec<u8>> {
+        let mut unsigned = transaction.clone();
+        unsigned.signature = String::new();
+        let json = serde_json::to_string(&unsigned).ok()?;
+        Some([chain_id, json.as_bytes()].concat())
+    }
+
     // VERIFY A TRANSACTION
-    pub fn verify_transaction(transaction: &Transaction) -> bool {
-        let signature_bin = hex::decode(&transaction.signature).unwrap();
+    /// Whether the transaction is signed by its sender for the chain `chain_id`
+    pub fn verify_transaction(transaction: &Transaction, chain_id: &[u8]) -> bool {
+        // a valid signature is worthless unless the signing key owns the sender address
+        if !Wallet::is_sender_key(transaction) {
+            return false;
+        }
 
-        let mut transaction_clone = transaction.clone();
-        transaction_clone.signature = String::new();
+        /*
+        the transaction id hashes the signature and key as text, so any other
+        encoding of the same signature would give the transaction another id
+        that anyone relaying it could pick. Only the lowercase hex that
+        sign_transaction writes is accepted, and of the two signatures (r, s)
+        and (r, n - s) that both verify, only the one with the low s.
+         */
+        if [&transaction.signature, &transaction.public_key]
+            .iter()
+            .any(|hex| hex.bytes().any(|b| b.is_ascii_uppercase()))
+        {
+            return false;
+        }
+
+        // malformed hex or key material simply fails verification
+        let Ok(signature_bin) = hex::decode(&transaction.signature) else {
+            return false;
+        };
 
-        let serialize_str = serde_json::to_string(&transaction_clone).unwrap();
-        let serialized = serialize_str.as_bytes();
+        let Some(serialized) = Wallet::signing_payload(transaction, chain_id) else {
+            return false;
+        };
 
         // Convert signature from hex string to Signature struct
-        let sig_array: [u8; 64] = signature_bin.try_into().unwrap();
-        let signature = Signature::from_bytes(&sig_array.into()).unwrap();
+        let Ok(sig_array) = <[u8; 64]>::try_from(signature_bin) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_bytes(&sig_array.into()) else {
+            return false;
+        };
+        if signature.normalize_s().is_some() {
+            return false;
+        }
 
-        let mut public_key_bin = hex::decode(&transaction.public_key).unwrap();
+        let Ok(mut public_key_bin) = hex::decode(&transaction.public_key) else {
+            return false;
+        };
         public_key_bin.insert(0, 0x04); // sec1 format [0x04 || x || y]
 
-        let public_key = VerifyingKey::from_sec1_bytes(&public_key_bin).unwrap();
+        let Ok(public_key) = VerifyingKey::from_sec1_bytes(&public_key_bin) else {
+            return false;
+        };
 
-        public_key.verify(serialized, &signature).is_ok()
+        public_key.verify(&serialized, &signature).is_ok()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN_ID: &[u8] = &[7; 32];
+
+    fn signed() -> Transaction {
+        let mut wallet = Wallet::new(Network::Devnet);
+        let recipient = Wallet::new(Network::Devnet).get_adress();
+        wallet.sign_transaction(&recipient, Amount::from_units(5), Amount::from_units(1), 0, CHAIN_ID)
+    }
+
+    fn decode_signature(transaction: &Transaction) -> Signature {
+        Signature::from_slice(&hex::decode(&transaction.signature).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn signed_transaction_verifies_on_its_chain_only() {
+        let transaction = signed();
+        assert!(Wallet::verify_transaction(&transaction, CHAIN_ID));
+        assert!(!Wallet::verify_transaction(&transaction, &[8; 32]));
+
+        let mut changed = transaction.clone();
+        changed.amount = Amount::from_units(6);
+        assert!(!Wallet::verify_transaction(&changed, CHAIN_ID));
+    }
+
+    #[test]
+    fn signatures_are_low_s() {
+        // half of the raw p256 signatures have a high s
+        for _ in 0..32 {
+            assert!(decode_signature(&signed()).normalize_s().is_none());
+        }
+    }
+
+    #[test]
+    fn high_s_signature_is_refused() {
+        let mut transaction = signed();
+        let signature = decode_signature(&transaction);
+        let (r, s) = signature.split_scalars();
+        let high = Signature::from_scalars(r, -s).unwrap();
+        assert!(high.normalize_s().is_some());
+
+        transaction.signature = hex::encode(high.to_bytes());
+        assert!(!Wallet::verify_transaction(&transaction, CHAIN_ID));
+    }
+
+    #[test]
+    fn uppercase_hex_is_refused() {
+        let transaction = signed();
+
+        let mut signature = transaction.clone();
+        signature.signature = signature.signature.to_uppercase();
+        assert!(!Wallet::verify_transaction(&signature, CHAIN_ID));
+
+        let mut public_key = transaction.clone();
+        public_key.public_key = public_key.public_key.to_uppercase();
+        assert!(!Wallet::verify_transaction(&public_key, CHAIN_ID));
+    }
+}